@@ -0,0 +1,339 @@
+use crate::inspect::Format;
+use crate::observer::Alert;
+use crate::plot::ImageFormat;
+use crate::optimizer::{MinConstraint, Objective, SearchParameter, Strategy};
+use crate::parser::{parse_duration, parse_timestamp};
+use crate::sweep::SweepParameter;
+use crate::whatif::Branch;
+use chrono::prelude::*;
+
+pub const USAGE: &str = "\
+Usage: resim [command] [file.reson] [key=value ...] [--tui] [--quiet | --verbose]
+
+Commands:
+  run       Simulate the model and write the output (default)
+  check     Parse the model and report problems, without simulating
+  inspect   Print the resources and processes of the model, or its graph
+  plot      Draw charts of the output of a run, one per unit
+  analyze   Estimate the highest process rates and resource flows per day, without simulating
+  sweep     Simulate every combination of sweep parameters
+  optimize  Search parameters for the best objective
+  whatif    Fork the simulation into branches and compare them
+
+Options:
+  reson_file=FILE      .reson file to simulate (default example/simple_pencil.reson)
+  start_time=TIME      Start of the simulation, 2024-01-01, 2024-01-01 09:00:00 (UTC)
+                       or 2024-01-01T09:00:00+01:00 (default January 1st of this year)
+  run_for=DURATION     How long to simulate, e.g. 86400, 12h, 90d, 6mo, 1y2w (default 31d)
+  end_time=TIME        Simulate until this time instead of for run_for
+  write_every=DURATION Interval between output rows (default 1h)
+  output=FILE          CSV output of run (default output.csv)
+  checkpoint=FILE      Save the simulation state at the end of run
+  resume=FILE          Continue a checkpoint instead of parsing a .reson file
+  ledger=DIR           Write the financial ledger at the end of run
+  report=FILE          Write an HTML report with charts at the end of run
+  alert=CONDITION      Print when a condition on the resources starts to hold during run,
+                       e.g. \"cad < 1000\" or \"wood + graphite >= 1e6\"
+  format=FORMAT        Output of inspect: text, dot (Graphviz) or mermaid (default text),
+                       of plot: png or svg (default png)
+  resources=R1,R2      Resources plot draws (default all)
+  out_dir=DIR          Results of sweep, optimize, whatif and plot (default the command name)
+  sweep=PATH=VALUES    Sweep parameter, v1,v2,... or start:end:step
+  param=PATH=MIN:MAX[:STEP]   Parameter for optimize
+  objective=SPEC       Objective for optimize, e.g. \"maximize final cad\"
+  constraint=RES>=MIN  Constraint for optimize
+  strategy=NAME        grid, random or evolution (default random)
+  iterations=N         Simulations for optimize (default 50)
+  seed=N               Random seed for optimize (default 0)
+  branch=NAME=MODS     What-if branch, e.g. rush=add:cad:5000,remove:sell_pencil
+  fork_after=DURATION  Time simulated before the what-if branches fork (default 0)
+  --tui                Show a dashboard while run simulates, which can pause, step a second,
+                       show the decay batches of a resource and stop the run early
+  -q, --quiet          Only print errors and results
+  -v, --verbose        Also print the parsed model
+  -h, --help           Print this help
+
+Exit codes: 0 on success, 1 if the model or a file can't be processed or the run was stopped,
+2 for invalid arguments. Ctrl-C stops a run, writing the output, checkpoint, ledger and report so far.
+";
+
+/// What the program is asked to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Run,
+    Check,
+    Inspect,
+    Analyze,
+    Plot,
+    Sweep,
+    Optimize,
+    WhatIf,
+}
+
+impl Command {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "run" => Some(Command::Run),
+            "check" => Some(Command::Check),
+            "inspect" => Some(Command::Inspect),
+            "analyze" => Some(Command::Analyze),
+            "plot" => Some(Command::Plot),
+            "sweep" => Some(Command::Sweep),
+            "optimize" => Some(Command::Optimize),
+            "whatif" => Some(Command::WhatIf),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Run => "run",
+            Command::Check => "check",
+            Command::Inspect => "inspect",
+            Command::Analyze => "analyze",
+            Command::Plot => "plot",
+            Command::Sweep => "sweep",
+            Command::Optimize => "optimize",
+            Command::WhatIf => "whatif",
+        }
+    }
+}
+
+/// How much is printed besides errors and results.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// The parsed command line.
+pub struct Options {
+    pub command: Command,
+    pub help: bool,
+    pub tui: bool,
+    pub verbosity: Verbosity,
+    pub reson_file: String,
+    pub start_time: DateTime<Utc>,
+    pub write_every: u64,
+    run_for: Option<u64>,
+    pub output: String,
+    pub checkpoint: Option<String>,
+    pub resume: Option<String>,
+    pub ledger: Option<String>,
+    pub report: Option<String>,
+    pub alerts: Vec<Alert>,
+    pub format: Format,
+    pub image_format: ImageFormat,
+    pub resources: Vec<String>,
+    format_spec: Option<String>,
+    end_time: Option<DateTime<Utc>>,
+    out_dir: Option<String>,
+    pub sweep_parameters: Vec<SweepParameter>,
+    pub search_parameters: Vec<SearchParameter>,
+    pub objective: Option<Objective>,
+    pub constraints: Vec<MinConstraint>,
+    pub strategy: Strategy,
+    pub iterations: usize,
+    pub seed: u64,
+    pub branches: Vec<Branch>,
+    pub fork_after: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            command: Command::Run,
+            help: false,
+            tui: false,
+            verbosity: Verbosity::Normal,
+            reson_file: "example/simple_pencil.reson".to_string(),
+            start_time: Utc::now().with_month(1).unwrap().with_day(1).unwrap().with_hour(0).unwrap().with_minute(0).unwrap().with_second(0).unwrap().with_nanosecond(0).unwrap(),
+            write_every: 3600,
+            run_for: None,
+            output: "output.csv".to_string(),
+            checkpoint: None,
+            resume: None,
+            ledger: None,
+            report: None,
+            alerts: Vec::new(),
+            format: Format::Text,
+            image_format: ImageFormat::Png,
+            resources: Vec::new(),
+            format_spec: None,
+            end_time: None,
+            out_dir: None,
+            sweep_parameters: Vec::new(),
+            search_parameters: Vec::new(),
+            objective: None,
+            constraints: Vec::new(),
+            strategy: Strategy::Random,
+            iterations: 50,
+            seed: 0,
+            branches: Vec::new(),
+            fork_after: 0,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the command line arguments without the program name.
+    ///
+    /// The command and the .reson file may be given by position, everything else is `key=value`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut command = None;
+        let mut reson_file = None;
+
+        for arg in args {
+            if let Some((key, value)) = arg.split_once('=') {
+                options.set(key.trim(), value.trim())?;
+            } else if arg.starts_with('-') {
+                match arg.as_str() {
+                    "-h" | "--help" => options.help = true,
+                    "--tui" => options.tui = true,
+                    "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+                    "-v" | "--verbose" => options.verbosity = Verbosity::Verbose,
+                    _ => return Err(format!("Unknown option {}", arg)),
+                }
+            } else if command.is_none() && reson_file.is_none() && Command::parse(arg).is_some() {
+                command = Command::parse(arg);
+            } else if arg == "help" && command.is_none() {
+                options.help = true;
+            } else if reson_file.is_none() && arg.ends_with(".reson") {
+                reson_file = Some(arg.clone());
+            } else {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+        }
+
+        if let Some(command) = command {
+            options.command = command;
+        }
+        if let Some(reson_file) = reson_file {
+            options.reson_file = reson_file;
+        }
+        if options.run_for.is_some() && options.end_time.is_some() {
+            return Err("Give either run_for or end_time, not both".to_string());
+        }
+        // A resumed run starts at the time of its checkpoint, which is only known once it is loaded
+        if options.resume.is_none() {
+            options.run_for(options.start_time)?;
+        }
+        // The format is read once the command is known, as it means something else for every command
+        if let Some(format_spec) = &options.format_spec {
+            match options.command {
+                Command::Inspect => options.format = Format::parse(format_spec)?,
+                Command::Plot => options.image_format = ImageFormat::parse(format_spec)?,
+                _ => return Err(format!("format is only used by inspect and plot, not {}", options.command.name())),
+            }
+        }
+        if options.tui && options.command != Command::Run {
+            return Err(format!("--tui only works with run, not {}", options.command.name()));
+        }
+        if !options.alerts.is_empty() && options.command != Command::Run {
+            return Err(format!("alert only works with run, not {}", options.command.name()));
+        }
+        if options.tui && !options.alerts.is_empty() {
+            return Err("alert prints to the terminal, which --tui draws over, so they can't be combined".to_string());
+        }
+        // Every branch writes to a file named after it
+        for (index, branch) in options.branches.iter().enumerate() {
+            if options.branches[..index].iter().any(|other| other.name == branch.name) {
+                return Err(format!("Branch {} is given twice", branch.name));
+            }
+        }
+        if options.write_every == 0 {
+            return Err("write_every must be at least 1 second".to_string());
+        }
+        if options.command == Command::Optimize && options.objective.is_none() && !options.help {
+            return Err("optimize needs an objective, e.g. objective=\"maximize final cad\"".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Sets the option for a `key=value` argument.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let duration = |value: &str| parse_duration(value)
+            .ok_or_else(|| format!("Invalid duration {} for {}, expected seconds or numbers with s, m, h, d, w, mo or y like 1y2w", value, key));
+        let time = |value: &str| parse_timestamp(value)
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp as i64, 0))
+            .ok_or_else(|| format!("Invalid {} {}, expected 2024-01-01, 2024-01-01 09:00:00 or 2024-01-01T09:00:00Z", key, value));
+        let number = |value: &str| value.parse::<u64>().map_err(|_| format!("Invalid number {} for {}", value, key));
+        match key {
+            "reson_file" => self.reson_file = value.to_string(),
+            "start_time" => self.start_time = time(value)?,
+            "end_time" => self.end_time = Some(time(value)?),
+            "write_every" => self.write_every = duration(value)?,
+            "run_for" => self.run_for = Some(duration(value)?),
+            "output" => self.output = value.to_string(),
+            "checkpoint" => self.checkpoint = Some(value.to_string()),
+            "resume" => self.resume = Some(value.to_string()),
+            "ledger" => self.ledger = Some(value.to_string()),
+            "report" => self.report = Some(value.to_string()),
+            "format" => self.format_spec = Some(value.to_string()),
+            "resources" => self.resources.extend(value.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string)),
+            "out_dir" => self.out_dir = Some(value.to_string()),
+            "sweep" => self.sweep_parameters.push(SweepParameter::parse(value)?),
+            "param" => self.search_parameters.push(SearchParameter::parse(value)?),
+            "objective" => self.objective = Some(Objective::parse(value)?),
+            "alert" => self.alerts.push(Alert::parse(value)?),
+            "constraint" => self.constraints.push(MinConstraint::parse(value)?),
+            "strategy" => self.strategy = Strategy::parse(value)?,
+            "iterations" => self.iterations = number(value)? as usize,
+            "seed" => self.seed = number(value)?,
+            "branch" => self.branches.push(Branch::parse(value)?),
+            "fork_after" => self.fork_after = duration(value)?,
+            _ => return Err(format!("Unknown argument {}", key)),
+        }
+        Ok(())
+    }
+
+    /// How long to simulate from `start`: `run_for`, until `end_time` or 31 days by default.
+    pub fn run_for(&self, start: DateTime<Utc>) -> Result<u64, String> {
+        match (self.run_for, self.end_time) {
+            (Some(run_for), _) => Ok(run_for),
+            (None, Some(end_time)) if end_time > start => Ok((end_time - start).num_seconds() as u64),
+            (None, Some(end_time)) => Err(format!("end_time {} is not after the start {}", end_time, start)),
+            (None, None) => Ok(86400 * 31),
+        }
+    }
+
+    /// Directory the results of sweeps, optimizations and what-if runs go to, named after the command by default.
+    pub fn out_dir(&self) -> String {
+        self.out_dir.clone().unwrap_or_else(|| self.command.name().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn run_for_and_end_time() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(parse(&[]).unwrap().run_for(start), Ok(86400 * 31));
+        assert_eq!(parse(&["run_for=1d12h"]).unwrap().run_for(start), Ok(86400 + 12 * 3600));
+        let options = parse(&["start_time=2024-01-01", "end_time=2024-01-03"]).unwrap();
+        assert_eq!(options.run_for(start), Ok(2 * 86400));
+        // Resumed runs count from the checkpoint
+        let options = parse(&["resume=run.json", "end_time=2024-01-03"]).unwrap();
+        assert_eq!(options.run_for(start + chrono::Duration::days(1)), Ok(86400));
+        assert!(options.run_for(start + chrono::Duration::days(3)).is_err());
+
+        assert!(parse(&["run_for=1d", "end_time=2024-01-03"]).is_err());
+        assert!(parse(&["start_time=2024-01-03", "end_time=2024-01-01"]).is_err());
+        assert!(parse(&["run_for=999999999999999y"]).is_err());
+    }
+
+    #[test]
+    fn branch_names_are_unique() {
+        assert_eq!(parse(&["whatif", "branch=a=add:cad:1", "branch=b"]).unwrap().branches.len(), 2);
+        assert!(parse(&["whatif", "branch=a=add:cad:1", "branch=a"]).is_err());
+        assert!(parse(&["whatif", "branch=../a"]).is_err());
+    }
+}
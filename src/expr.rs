@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+
+/// An arithmetic expression over numbers and named variables, like `0.8 * wood_price + 2`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+impl Expr {
+    /// Parses an expression with `+ - * /`, parentheses and variables (names may contain `.` and `@`).
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut position = 0;
+        let expr = parse_sum(&tokens, &mut position)?;
+        if position < tokens.len() {
+            return Err(format!("Unexpected {} in expression {}", tokens[position], text));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluates the expression, looking up the values of variables.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Variable(name) => lookup(name).ok_or_else(|| format!("Unknown variable {}", name)),
+            Expr::Negate(expr) => Ok(-expr.eval(lookup)?),
+            Expr::Binary(left, operator, right) => {
+                let (left, right) = (left.eval(lookup)?, right.eval(lookup)?);
+                Ok(match operator {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                })
+            }
+        }
+    }
+
+    /// Returns the expression with every variable renamed.
+    pub fn rename_variables(self, rename: &dyn Fn(&str) -> String) -> Expr {
+        match self {
+            Expr::Number(value) => Expr::Number(value),
+            Expr::Variable(name) => Expr::Variable(rename(&name)),
+            Expr::Negate(expr) => Expr::Negate(Box::new(expr.rename_variables(rename))),
+            Expr::Binary(left, operator, right) => {
+                Expr::Binary(Box::new(left.rename_variables(rename)), operator, Box::new(right.rename_variables(rename)))
+            }
+        }
+    }
+
+    /// Returns the names of all variables in the expression.
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Variable(name) => vec![name.as_str()],
+            Expr::Negate(expr) => expr.variables(),
+            Expr::Binary(left, _, right) => {
+                let mut variables = left.variables();
+                variables.extend(right.variables());
+                variables
+            }
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(c) {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || "_.@".contains(c) {
+            let mut token = String::new();
+            if c.is_ascii_digit() || c == '.' {
+                // A number, whose exponent may have a sign as in `5e-3`
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    token.push(c);
+                    chars.next();
+                }
+                let mut exponent = chars.clone();
+                if exponent.next().is_some_and(|c| c == 'e' || c == 'E') {
+                    if exponent.peek().is_some_and(|c| *c == '+' || *c == '-') {
+                        exponent.next();
+                    }
+                    if exponent.peek().is_some_and(char::is_ascii_digit) {
+                        while chars.peek().is_some_and(|c| !c.is_ascii_digit()) {
+                            token.extend(chars.next());
+                        }
+                    }
+                }
+            }
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || "_.@".contains(c)) {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            return Err(format!("Unexpected character {} in expression {}", c, text));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_sum(tokens: &[String], position: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_product(tokens, position)?;
+    while let Some(operator) = tokens.get(*position).filter(|t| *t == "+" || *t == "-") {
+        *position += 1;
+        let operator = operator.chars().next().unwrap();
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(parse_product(tokens, position)?));
+    }
+    Ok(expr)
+}
+
+fn parse_product(tokens: &[String], position: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_factor(tokens, position)?;
+    while let Some(operator) = tokens.get(*position).filter(|t| *t == "*" || *t == "/") {
+        *position += 1;
+        let operator = operator.chars().next().unwrap();
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(parse_factor(tokens, position)?));
+    }
+    Ok(expr)
+}
+
+fn parse_factor(tokens: &[String], position: &mut usize) -> Result<Expr, String> {
+    let token = tokens.get(*position).ok_or("Unexpected end of expression")?;
+    *position += 1;
+    match token.as_str() {
+        "-" => Ok(Expr::Negate(Box::new(parse_factor(tokens, position)?))),
+        "(" => {
+            let expr = parse_sum(tokens, position)?;
+            if tokens.get(*position).map(String::as_str) != Some(")") {
+                return Err("Missing ) in expression".to_string());
+            }
+            *position += 1;
+            Ok(expr)
+        }
+        _ => match token.parse::<f64>() {
+            Ok(value) => Ok(Expr::Number(value)),
+            Err(_) if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => Ok(Expr::Variable(token.clone())),
+            Err(_) => Err(format!("Invalid number {} in expression", token)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<f64, String> {
+        let lookup = |name: &str| match name {
+            "wood" => Some(4.0),
+            "line_a.pencil@store" => Some(10.0),
+            _ => None,
+        };
+        Expr::parse(text)?.eval(&lookup)
+    }
+
+    #[test]
+    fn precedence_and_parentheses() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval("8 / 4 / 2"), Ok(1.0));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(eval("-wood * 2"), Ok(-8.0));
+        assert_eq!(eval("2*-(wood-1)"), Ok(-6.0));
+    }
+
+    #[test]
+    fn numbers_with_exponents() {
+        assert_eq!(eval("5e-3"), Ok(0.005));
+        assert_eq!(eval("1e6 + 2E+2"), Ok(1_000_200.0));
+        assert_eq!(eval("wood*1.5e1"), Ok(60.0));
+        assert_eq!(eval(".5"), Ok(0.5));
+        assert!(eval("5e").is_err());
+        assert!(eval("5e-").is_err());
+        assert!(eval("1.2.3").is_err());
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(eval("line_a.pencil@store / 2"), Ok(5.0));
+        assert_eq!(Expr::parse("wood + cad * wood").unwrap().variables(), vec!["wood", "cad", "wood"]);
+        assert_eq!(eval("graphite"), Err("Unknown variable graphite".to_string()));
+        let renamed = Expr::parse("wood + 1").unwrap().rename_variables(&|name| format!("line_a.{}", name));
+        assert_eq!(renamed.variables(), vec!["line_a.wood"]);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(Expr::parse("").is_err());
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 + 2)").is_err());
+        assert!(Expr::parse("1 $ 2").is_err());
+        assert!(Expr::parse("wood wood").is_err());
+    }
+}
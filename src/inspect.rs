@@ -0,0 +1,387 @@
+use crate::resource::{EventTime, InstrumentKind, Model, Process};
+use chrono::DateTime;
+use indexmap::IndexMap;
+use std::fmt::Write;
+
+/// Formats seconds in the largest unit they divide into, like `30d` or `90m`.
+pub fn format_duration(seconds: u64) -> String {
+    for (unit, length) in [("y", 31557600), ("w", 604800), ("d", 86400), ("h", 3600), ("m", 60)] {
+        if seconds > 0 && seconds.is_multiple_of(length) {
+            return format!("{}{}", seconds / length, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
+
+/// Formats a list of quantities like `wood 1, graphite 0.5`.
+fn format_quantities(quantities: &IndexMap<String, f64>) -> String {
+    quantities.iter().map(|(name, quantity)| format!("{} {}", name, quantity)).collect::<Vec<_>>().join(", ")
+}
+
+/// Describes a process in a few indented lines.
+fn describe_process(text: &mut String, name: &str, process: &Process) {
+    let mut schedule = if process.on_use > 0.0 {
+        format!("on use of {}", process.on_use)
+    } else {
+        format!("every {}", format_duration(process.period))
+    };
+    if process.period_delta > 0 {
+        write!(schedule, " ± {}", format_duration(process.period_delta)).unwrap();
+    }
+    if !process.enabled {
+        schedule.push_str(", disabled");
+    }
+    writeln!(text, "  {}: {}", name, schedule).unwrap();
+    for (label, quantities) in [("use", &process.input), ("produce", &process.output), ("catalyst", &process.catalyst)] {
+        if !quantities.is_empty() {
+            writeln!(text, "    {}: {}", label, format_quantities(quantities)).unwrap();
+        }
+    }
+    if process.lead_time > 0 {
+        writeln!(text, "    lead time: {}", format_duration(process.lead_time)).unwrap();
+    }
+    if let Some(capacity) = process.capacity {
+        writeln!(text, "    capacity: {}", capacity).unwrap();
+    }
+    if let Some(overdraft) = &process.overdraft {
+        writeln!(text, "    overdraft of: {}", overdraft).unwrap();
+    }
+    if let Some(demand) = &process.demand {
+        let expire = demand.expire.map_or("never".to_string(), format_duration);
+        writeln!(text, "    demand: {} orders, expire {}", demand.orders, expire).unwrap();
+    }
+    if let Some(transport) = &process.transport {
+        writeln!(text, "    transport: {} -> {}", transport.from, transport.to).unwrap();
+    }
+}
+
+/// Describes the resources, processes, events and instruments of a model.
+pub fn describe(model: &Model) -> String {
+    let mut text = String::new();
+    writeln!(text, "Resources ({}):", model.resources.len()).unwrap();
+    for (name, resource) in &model.resources {
+        write!(text, "  {}: {} {}", name, resource.amount, resource.unit).unwrap();
+        if resource.max < f64::MAX {
+            write!(text, ", max {}", resource.max).unwrap();
+        }
+        if resource.min != 0.0 {
+            write!(text, ", min {}", if resource.min == f64::MIN { "unlimited".to_string() } else { resource.min.to_string() }).unwrap();
+        }
+        if resource.life > 0 {
+            write!(text, ", life {}", format_duration(resource.life)).unwrap();
+        }
+        if resource.value.is_some() {
+            text.push_str(", valued");
+        }
+        text.push('\n');
+    }
+
+    writeln!(text, "Processes ({}):", model.processes.len() + model.on_use_processes.len()).unwrap();
+    for (name, process) in model.processes.iter().chain(&model.on_use_processes) {
+        describe_process(&mut text, name, process);
+    }
+
+    if !model.events.is_empty() {
+        writeln!(text, "Events ({}):", model.events.len()).unwrap();
+        for (name, event) in &model.events {
+            let time = match event.time {
+                EventTime::At(timestamp) => DateTime::from_timestamp(timestamp as i64, 0).map_or(timestamp.to_string(), |time| time.to_string()),
+                EventTime::After(offset) => format!("after {}", format_duration(offset)),
+            };
+            writeln!(text, "  {}: {}, {} modifications", name, time, event.modifications.len()).unwrap();
+        }
+    }
+
+    if !model.instruments.is_empty() {
+        writeln!(text, "Instruments ({}):", model.instruments.len()).unwrap();
+        for (name, instrument) in &model.instruments {
+            let kind = match instrument.kind {
+                InstrumentKind::Loan => "loan",
+                InstrumentKind::Savings => "savings",
+            };
+            writeln!(text, "  {}: {} of {} at {} % every {}", name, kind, instrument.principal, instrument.interest * 100.0, format_duration(instrument.period)).unwrap();
+        }
+    }
+
+    if let Some(base_currency) = &model.base_currency {
+        writeln!(text, "Base currency: {}", base_currency).unwrap();
+    }
+    text
+}
+
+/// Replaces everything but letters and digits, for ids in DOT and Mermaid.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+/// Returns the id of a node, prefixed so resources and processes with the same name don't collide.
+fn node_id(name: &str, is_process: bool) -> String {
+    format!("{}_{}", if is_process { "p" } else { "r" }, sanitize(name))
+}
+
+/// What `inspect` prints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Dot,
+    Mermaid,
+}
+
+impl Format {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "text" => Ok(Format::Text),
+            "dot" => Ok(Format::Dot),
+            "mermaid" => Ok(Format::Mermaid),
+            _ => Err(format!("Unknown format {}, expected text, dot or mermaid", spec)),
+        }
+    }
+}
+
+/// How a process is linked to a resource or to an on-use process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Link {
+    Use,
+    Produce,
+    Catalyst,
+    OnUse,
+}
+
+/// The resources and processes of a model and how they are linked.
+pub struct Graph {
+    /// Resources with their location.
+    resources: Vec<(String, Option<String>)>,
+    /// Processes and whether they are on-use processes.
+    processes: Vec<(String, bool)>,
+    /// Links as (resource or on-use process, process, link). Produced resources are the first
+    /// element as well, the link gives the direction.
+    links: Vec<(String, String, Link)>,
+    /// Resources some process produces but none uses up or is catalyzed by.
+    pub produced_only: Vec<String>,
+    /// Resources some process uses up but none produces.
+    pub consumed_only: Vec<String>,
+}
+
+impl Graph {
+    /// Builds the graph from the inputs, outputs and catalysts of the processes.
+    pub fn new(model: &Model) -> Self {
+        let mut links = Vec::new();
+        for (name, process) in model.processes.iter().chain(&model.on_use_processes) {
+            for resource_name in process.input.keys() {
+                let link = if model.on_use_processes.contains_key(resource_name) { Link::OnUse } else { Link::Use };
+                links.push((resource_name.clone(), name.clone(), link));
+            }
+            for resource_name in process.output.keys() {
+                links.push((resource_name.clone(), name.clone(), Link::Produce));
+            }
+            for resource_name in process.catalyst.keys() {
+                links.push((resource_name.clone(), name.clone(), Link::Catalyst));
+            }
+        }
+
+        let has_link = |resource_name: &str, kind: Link| links.iter().any(|(name, _, link)| name == resource_name && *link == kind);
+        let produced_only = model.resources.keys()
+            .filter(|name| has_link(name, Link::Produce) && !has_link(name, Link::Use) && !has_link(name, Link::Catalyst))
+            .cloned()
+            .collect();
+        let consumed_only = model.resources.keys()
+            .filter(|name| has_link(name, Link::Use) && !has_link(name, Link::Produce))
+            .cloned()
+            .collect();
+
+        Graph {
+            resources: model.resources.iter().map(|(name, resource)| (name.clone(), resource.location.clone())).collect(),
+            processes: model.processes.keys().map(|name| (name.clone(), false))
+                .chain(model.on_use_processes.keys().map(|name| (name.clone(), true)))
+                .collect(),
+            links,
+            produced_only,
+            consumed_only,
+        }
+    }
+
+    /// Returns the locations of the resources in order of appearance, `None` for resources without one.
+    fn locations(&self) -> Vec<Option<&str>> {
+        let mut locations: Vec<Option<&str>> = Vec::new();
+        for (_, location) in &self.resources {
+            if !locations.contains(&location.as_deref()) {
+                locations.push(location.as_deref());
+            }
+        }
+        locations
+    }
+
+    /// Returns the ids of both ends of a link, in the direction things flow.
+    fn link_ends(&self, (from, process, link): &(String, String, Link)) -> (String, String) {
+        match link {
+            Link::Produce => (node_id(process, true), node_id(from, false)),
+            Link::OnUse => (node_id(from, true), node_id(process, true)),
+            Link::Use | Link::Catalyst => (node_id(from, false), node_id(process, true)),
+        }
+    }
+
+    /// Formats the graph for Graphviz. Resources produced but never used up are yellow,
+    /// resources used up but never produced red, and resources at a location are clustered.
+    pub fn to_dot(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "digraph model {{").unwrap();
+        writeln!(text, "    rankdir=LR;").unwrap();
+        for location in self.locations() {
+            let indent = if let Some(location) = location {
+                writeln!(text, "    subgraph {:?} {{", format!("cluster_{}", location)).unwrap();
+                writeln!(text, "        label={:?};", location).unwrap();
+                "        "
+            } else {
+                "    "
+            };
+            for (name, _) in self.resources.iter().filter(|(_, l)| l.as_deref() == location) {
+                let fill = if self.produced_only.contains(name) {
+                    ", style=filled, fillcolor=\"#fde68a\""
+                } else if self.consumed_only.contains(name) {
+                    ", style=filled, fillcolor=\"#fca5a5\""
+                } else {
+                    ""
+                };
+                writeln!(text, "{}{} [label={:?}, shape=ellipse{}];", indent, node_id(name, false), name, fill).unwrap();
+            }
+            if location.is_some() {
+                writeln!(text, "    }}").unwrap();
+            }
+        }
+        for (name, on_use) in &self.processes {
+            let style = if *on_use { ", style=rounded" } else { "" };
+            writeln!(text, "    {} [label={:?}, shape=box{}];", node_id(name, true), name, style).unwrap();
+        }
+        for link in &self.links {
+            let (from, to) = self.link_ends(link);
+            let attributes = match link.2 {
+                Link::Catalyst => " [style=dashed, label=\"catalyst\"]",
+                Link::OnUse => " [style=dotted, label=\"on use\"]",
+                Link::Use | Link::Produce => "",
+            };
+            writeln!(text, "    {} -> {}{};", from, to, attributes).unwrap();
+        }
+        writeln!(text, "}}").unwrap();
+        text
+    }
+
+    /// Formats the graph as a Mermaid flowchart, highlighted and grouped like `to_dot`.
+    pub fn to_mermaid(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "flowchart LR").unwrap();
+        for location in self.locations() {
+            let indent = if let Some(location) = location {
+                writeln!(text, "    subgraph l_{}[\"{}\"]", sanitize(location), location).unwrap();
+                "        "
+            } else {
+                "    "
+            };
+            for (name, _) in self.resources.iter().filter(|(_, l)| l.as_deref() == location) {
+                writeln!(text, "{}{}([\"{}\"])", indent, node_id(name, false), name).unwrap();
+            }
+            if location.is_some() {
+                writeln!(text, "    end").unwrap();
+            }
+        }
+        for (name, on_use) in &self.processes {
+            let (open, close) = if *on_use { ("(", ")") } else { ("[", "]") };
+            writeln!(text, "    {}{}\"{}\"{}", node_id(name, true), open, name, close).unwrap();
+        }
+        for link in &self.links {
+            let (from, to) = self.link_ends(link);
+            let arrow = match link.2 {
+                Link::Catalyst => "-. catalyst .->",
+                Link::OnUse => "-. on use .->",
+                Link::Use | Link::Produce => "-->",
+            };
+            writeln!(text, "    {} {} {}", from, arrow, to).unwrap();
+        }
+        writeln!(text, "    classDef produced_only fill:#fde68a").unwrap();
+        writeln!(text, "    classDef consumed_only fill:#fca5a5").unwrap();
+        for (class, names) in [("produced_only", &self.produced_only), ("consumed_only", &self.consumed_only)] {
+            if !names.is_empty() {
+                let ids: Vec<String> = names.iter().map(|name| node_id(name, false)).collect();
+                writeln!(text, "    class {} {}", ids.join(","), class).unwrap();
+            }
+        }
+        text
+    }
+
+    /// Lists the resources which are only produced or only used up.
+    pub fn describe_dead_ends(&self) -> String {
+        let mut text = String::new();
+        if !self.produced_only.is_empty() {
+            writeln!(text, "Produced but never used: {}", self.produced_only.join(", ")).unwrap();
+        }
+        if !self.consumed_only.is_empty() {
+            writeln!(text, "Used up but never produced: {}", self.consumed_only.join(", ")).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+
+    fn graph() -> Graph {
+        let model = parse_model("wood\n    resource\n\nmachine\n    resource\n\npencil\n    resource\n    locations store\n\nmake_pencil\n    process\n    use\n        wood 1\n    produce\n        pencil@store 1\n    catalyze\n        machine 1\n    period 1 h\n").unwrap();
+        Graph::new(&model)
+    }
+
+    #[test]
+    fn dot_clusters_locations_and_highlights_dead_ends() {
+        assert_eq!(graph().to_dot(), "\
+digraph model {
+    rankdir=LR;
+    r_wood [label=\"wood\", shape=ellipse, style=filled, fillcolor=\"#fca5a5\"];
+    r_machine [label=\"machine\", shape=ellipse];
+    subgraph \"cluster_store\" {
+        label=\"store\";
+        r_pencil_store [label=\"pencil@store\", shape=ellipse, style=filled, fillcolor=\"#fde68a\"];
+    }
+    p_make_pencil [label=\"make_pencil\", shape=box];
+    r_wood -> p_make_pencil;
+    p_make_pencil -> r_pencil_store;
+    r_machine -> p_make_pencil [style=dashed, label=\"catalyst\"];
+}
+");
+    }
+
+    #[test]
+    fn mermaid_groups_locations_and_classes_dead_ends() {
+        assert_eq!(graph().to_mermaid(), "\
+flowchart LR
+    r_wood([\"wood\"])
+    r_machine([\"machine\"])
+    subgraph l_store[\"store\"]
+        r_pencil_store([\"pencil@store\"])
+    end
+    p_make_pencil[\"make_pencil\"]
+    r_wood --> p_make_pencil
+    p_make_pencil --> r_pencil_store
+    r_machine -. catalyst .-> p_make_pencil
+    classDef produced_only fill:#fde68a
+    classDef consumed_only fill:#fca5a5
+    class r_pencil_store produced_only
+    class r_wood consumed_only
+");
+    }
+
+    #[test]
+    fn dead_ends() {
+        let graph = graph();
+        assert_eq!(graph.produced_only, ["pencil@store"]);
+        assert_eq!(graph.consumed_only, ["wood"]);
+        assert_eq!(graph.describe_dead_ends(), "Produced but never used: pencil@store\nUsed up but never produced: wood\n");
+    }
+
+    #[test]
+    fn durations_use_the_largest_unit() {
+        assert_eq!(format_duration(30 * 86400), "30d");
+        assert_eq!(format_duration(90 * 60), "90m");
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(61), "61s");
+    }
+}
@@ -0,0 +1,162 @@
+use chrono::DateTime;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Money moved by a process in one write interval. `debit` is money going out of the
+/// resource and `credit` money coming in, both in the currency of the resource.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub period_start: u64,
+    pub period_end: u64,
+    pub process: String,
+    pub resource: String,
+    pub debit: f64,
+    pub credit: f64,
+    /// Credit minus debit in the base currency, at the rates of the moments the money moved.
+    pub net_base: Option<f64>,
+}
+
+/// Tracks which process debited or credited every monetary resource.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    pub enabled: bool,
+    /// Entries of the write interval in progress.
+    pub current: Vec<LedgerEntry>,
+    pub entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    /// Records money moved by a process, `rate` converting it into the base currency if there is one.
+    pub fn record(&mut self, process: &str, resource: &str, debit: f64, credit: f64, rate: Option<f64>) {
+        if debit == 0.0 && credit == 0.0 {
+            return;
+        }
+        let index = match self.current.iter().position(|entry| entry.process == process && entry.resource == resource) {
+            Some(index) => index,
+            None => {
+                self.current.push(LedgerEntry {
+                    process: process.to_string(),
+                    resource: resource.to_string(),
+                    net_base: rate.map(|_| 0.0),
+                    ..Default::default()
+                });
+                self.current.len() - 1
+            }
+        };
+        let entry = &mut self.current[index];
+        entry.debit += debit;
+        entry.credit += credit;
+        if let (Some(net_base), Some(rate)) = (entry.net_base.as_mut(), rate) {
+            *net_base += (credit - debit) * rate;
+        }
+    }
+
+    /// Closes the write interval, moving its entries into the ledger.
+    pub fn close_period(&mut self, period_start: u64, period_end: u64) {
+        for mut entry in self.current.drain(..) {
+            entry.period_start = period_start;
+            entry.period_end = period_end;
+            self.entries.push(entry);
+        }
+    }
+
+    /// Sums the entries by process and resource.
+    pub fn by_process(&self) -> Vec<LedgerEntry> {
+        self.summarize(|entry| (entry.process.clone(), entry.resource.clone()))
+    }
+
+    /// Sums the entries by write interval and resource.
+    pub fn by_period(&self) -> Vec<LedgerEntry> {
+        let mut summaries = self.summarize(|entry| (entry.period_start.to_string(), entry.resource.clone()));
+        for summary in &mut summaries {
+            summary.process.clear();
+        }
+        summaries
+    }
+
+    fn summarize<K>(&self, key: K) -> Vec<LedgerEntry>
+    where
+        K: Fn(&LedgerEntry) -> (String, String),
+    {
+        let mut summaries: IndexMap<(String, String), LedgerEntry> = IndexMap::new();
+        for entry in &self.entries {
+            let summary = summaries.entry(key(entry)).or_insert_with(|| LedgerEntry {
+                period_start: entry.period_start,
+                period_end: entry.period_end,
+                process: entry.process.clone(),
+                resource: entry.resource.clone(),
+                net_base: entry.net_base.map(|_| 0.0),
+                ..Default::default()
+            });
+            summary.period_start = summary.period_start.min(entry.period_start);
+            summary.period_end = summary.period_end.max(entry.period_end);
+            summary.debit += entry.debit;
+            summary.credit += entry.credit;
+            if let (Some(net_base), Some(entry_net_base)) = (summary.net_base.as_mut(), entry.net_base) {
+                *net_base += entry_net_base;
+            }
+        }
+        summaries.into_values().collect()
+    }
+
+    /// Writes `ledger.csv`, `ledger.json` and the profit and loss summaries
+    /// `pnl_by_process.csv` and `pnl_by_period.csv` into the directory.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let by_process = self.by_process();
+        let by_period = self.by_period();
+
+        write_entries_csv(&dir.join("ledger.csv"), &self.entries, true, true)?;
+        write_entries_csv(&dir.join("pnl_by_process.csv"), &by_process, false, true)?;
+        write_entries_csv(&dir.join("pnl_by_period.csv"), &by_period, true, false)?;
+
+        let mut file = io::BufWriter::new(File::create(dir.join("ledger.json"))?);
+        let json = serde_json::json!({
+            "entries": self.entries,
+            "by_process": by_process,
+            "by_period": by_period,
+        });
+        serde_json::to_writer_pretty(&mut file, &json)?;
+        file.flush()
+    }
+}
+
+/// Formats a timestamp like the time column of the simulation output.
+fn format_timestamp(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0).map_or(timestamp.to_string(), |time| time.to_string())
+}
+
+/// Writes entries as CSV, optionally without the period or process columns for summaries.
+fn write_entries_csv(path: &Path, entries: &[LedgerEntry], with_period: bool, with_process: bool) -> io::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let mut headers = Vec::new();
+    if with_period {
+        headers.extend(["period_start", "period_end"]);
+    }
+    if with_process {
+        headers.push("process");
+    }
+    headers.extend(["resource", "debit", "credit", "net", "net_base"]);
+    writer.write_record(&headers)?;
+
+    for entry in entries {
+        let mut record = Vec::new();
+        if with_period {
+            record.push(format_timestamp(entry.period_start));
+            record.push(format_timestamp(entry.period_end));
+        }
+        if with_process {
+            record.push(entry.process.clone());
+        }
+        record.push(entry.resource.clone());
+        record.push(entry.debit.to_string());
+        record.push(entry.credit.to_string());
+        record.push((entry.credit - entry.debit).to_string());
+        record.push(entry.net_base.map_or(String::new(), |net_base| net_base.to_string()));
+        writer.write_record(&record)?;
+    }
+    writer.flush()
+}
@@ -1,55 +1,244 @@
+mod cli;
+mod expr;
+mod inspect;
+mod ledger;
+mod observer;
+mod optimizer;
+mod parameter;
 mod parser;
+mod plot;
+mod report;
 mod resource;
 mod simulation;
+mod sweep;
+mod throughput;
+mod tui;
+mod units;
+mod whatif;
 
-use simulation::Simulation;
+use cli::{Command, Options, Verbosity, USAGE};
+use inspect::{format_duration, Format, Graph};
+use optimizer::Optimizer;
+use simulation::{CancelToken, Simulation};
 use parser::parse_simulation_file;
+use plot::{build_charts, write_charts, Output};
+use resource::Model;
+use sweep::run_sweep;
+use throughput::Throughput;
+use whatif::run_whatif;
 use std::env;
-use chrono::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-fn main() {
+/// Time between two updates of the progress line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+fn main() -> ExitCode {
     // Collect command line arguments
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\nRun `resim --help` for the usage.", e);
+            return ExitCode::from(2);
+        }
+    };
+    if options.help {
+        print!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
 
-    // Default values
-    let default_reson_file = "example/simple_pencil.reson".to_string();
-    let default_start_time = Utc::now().with_month(1).unwrap().with_day(1).unwrap().with_hour(0).unwrap().with_minute(0).unwrap().with_second(0).unwrap();
-    let default_write_every = 3600;
-    let default_run_for = 86400 * 31;
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    // Parse command line arguments with keywords
-    let mut reson_file = default_reson_file.clone();
-    let mut start_time = default_start_time;
-    let mut write_every = default_write_every;
-    let mut run_for = default_run_for;
+/// Runs the command, returning whether it succeeded.
+fn run(options: &Options) -> io::Result<bool> {
+    let out_dir = options.out_dir();
 
-    for arg in &args[1..] {
-        if let Some((key, value)) = arg.split_once('=') {
-            let key = key.trim();
-            let value = value.trim();
-            match key {
-            "reson_file" => reson_file = value.to_string(),
-            "start_time" => start_time = DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc),
-            "write_every" => write_every = value.parse().unwrap(),
-            "run_for" => run_for = value.parse().unwrap(),
-            _ => eprintln!("Unknown argument: {}", key),
+    // Fork the model, or a checkpointed run, into what-if branches
+    if options.command == Command::WhatIf {
+        let mut sim = match &options.resume {
+            Some(resume) => Simulation::load_checkpoint(Path::new(resume))?,
+            None => {
+                let model = parse_model(options)?;
+                let mut sim = Simulation::new(model, Box::new(io::sink()));
+                sim.set_start_time(options.start_time);
+                sim
             }
-        }
+        };
+        sim.write_every = options.write_every;
+        let run_for = options.run_for(sim.time).map_err(io::Error::other)?;
+        run_whatif(sim, &options.branches, options.fork_after, run_for, Path::new(&out_dir), options.verbosity == Verbosity::Quiet)?;
+        return Ok(true);
+    }
+
+    // Continue a checkpointed run, appending to its output
+    if let (Command::Run, Some(resume)) = (options.command, &options.resume) {
+        let mut sim = Simulation::load_checkpoint(Path::new(resume))?;
+        let write_headers = !Path::new(&options.output).exists();
+        let file = OpenOptions::new().create(true).append(true).open(&options.output)?;
+        sim.set_writer(Box::new(file), write_headers);
+        return simulate(&mut sim, options);
     }
 
     // Parse the .reson file
-    let (resources, processes, on_use_processes) = parse_simulation_file(&reson_file).unwrap();
-    println!("Resources: {:#?}", resources);
-    println!("Processes: {:#?}", processes);
+    let model = parse_model(options)?;
 
-    // Initialize the simulation
-    let mut sim = Simulation::new(resources, processes, on_use_processes);
+    match options.command {
+        Command::Check => {
+            let problems = model.problems();
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if problems.is_empty() && options.verbosity > Verbosity::Quiet {
+                println!("{}: {} resources, {} processes, no problems found", options.reson_file, model.resources.len(), model.processes.len() + model.on_use_processes.len());
+            }
+            Ok(problems.is_empty())
+        }
+        Command::Inspect => {
+            let graph = Graph::new(&model);
+            match options.format {
+                Format::Text => print!("{}{}", inspect::describe(&model), graph.describe_dead_ends()),
+                Format::Dot => print!("{}", graph.to_dot()),
+                Format::Mermaid => print!("{}", graph.to_mermaid()),
+            }
+            Ok(true)
+        }
+        Command::Analyze => {
+            print!("{}", Throughput::analyze(&model).describe());
+            Ok(true)
+        }
+        Command::Plot => {
+            let output = Output::read(Path::new(&options.output))?;
+            let charts = build_charts(&output, &model, &options.resources);
+            for path in write_charts(&charts, Path::new(&out_dir), options.image_format)? {
+                if options.verbosity > Verbosity::Quiet {
+                    println!("Wrote {}", path.display());
+                }
+            }
+            Ok(true)
+        }
+        Command::Sweep => {
+            let run_for = options.run_for(options.start_time).map_err(io::Error::other)?;
+            run_sweep(&model, &options.sweep_parameters, options.start_time, options.write_every, run_for, Path::new(&out_dir), options.verbosity == Verbosity::Quiet)?;
+            Ok(true)
+        }
+        Command::Optimize => {
+            let optimizer = Optimizer {
+                model,
+                parameters: options.search_parameters.clone(),
+                objective: options.objective.clone().unwrap(),
+                constraints: options.constraints.clone(),
+                start_time: options.start_time,
+                run_for: options.run_for(options.start_time).map_err(io::Error::other)?,
+                quiet: options.verbosity == Verbosity::Quiet,
+            };
+            let best = optimizer.optimize(options.strategy, options.iterations, options.seed)?;
+            println!("Best: {} -> {}", optimizer.describe(&best.values), best.objective);
+            if best.violation > 0.0 {
+                println!("No feasible configuration found, best one violates the constraints by {}", best.violation);
+            }
+            Ok(true)
+        }
+        Command::Run | Command::WhatIf => {
+            if options.verbosity == Verbosity::Verbose {
+                println!("Resources: {:#?}", model.resources);
+                println!("Processes: {:#?}", model.processes);
+                println!("Events: {:#?}", model.events);
+            }
+
+            // Initialize the simulation
+            let file = File::create(&options.output)?;
+            let mut sim = Simulation::new(model, Box::new(file));
+
+            // Setting the simulation time
+            sim.set_start_time(options.start_time);
+            simulate(&mut sim, options)
+        }
+    }
+}
 
-    // Setting the simulation time
-    sim.set_start_time(start_time);
+/// Parses the .reson file, printing the lines it ignored as warnings. `check` lists them with the problems instead.
+fn parse_model(options: &Options) -> io::Result<Model> {
+    let model = parse_simulation_file(&options.reson_file)?;
+    if options.command != Command::Check {
+        for warning in &model.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+    Ok(model)
+}
 
-    sim.write_every = write_every;
-    sim.display_state();
-    sim.run(run_for);
-    sim.display_state();
+/// Runs a simulation for `run_for`, then writes the ledger and checkpoint if asked for.
+/// Returns whether the whole run finished, these are written for a stopped run as well.
+fn simulate(sim: &mut Simulation, options: &Options) -> io::Result<bool> {
+    sim.write_every = options.write_every;
+    let run_for = options.run_for(sim.time).map_err(io::Error::other)?;
+    sim.ledger.enabled |= options.ledger.is_some();
+    for alert in &options.alerts {
+        alert.check_names(sim).map_err(io::Error::other)?;
+        sim.add_observer(Box::new(alert.clone()));
+    }
+    // The report describes the model as it was before the run
+    let model = options.report.as_ref().map(|_| sim.model());
+    if options.verbosity > Verbosity::Quiet {
+        sim.display_state();
+    }
+    let started = Instant::now();
+    let start = sim.time;
+    let completed = if options.tui {
+        tui::run(sim, run_for)?
+    } else {
+        // Ctrl-C stops the run at the next second, the output and everything below then hold the run until there
+        let cancel = CancelToken::default();
+        let handler = cancel.clone();
+        ctrlc::set_handler(move || handler.cancel()).map_err(io::Error::other)?;
+        let show_progress = options.verbosity > Verbosity::Quiet && io::stderr().is_terminal();
+        let mut shown = Instant::now();
+        let completed = sim.run_with(run_for, &cancel, |progress| {
+            if show_progress && shown.elapsed() >= PROGRESS_INTERVAL {
+                let speed = progress.simulated as f64 / started.elapsed().as_secs_f64();
+                eprint!("\r{:5.1} %  {}  {:.0} simulated s/s", 100.0 * progress.fraction(), progress.time, speed);
+                shown = Instant::now();
+            }
+        });
+        if show_progress {
+            // Clear the progress line
+            eprint!("\r\x1b[2K");
+        }
+        completed
+    };
+    if !completed {
+        println!("Stopped at {} after {} of {}", sim.time, format_duration((sim.time - start).num_seconds() as u64), format_duration(run_for));
+    }
+    if options.verbosity > Verbosity::Quiet {
+        println!("Simulation took {} seconds", started.elapsed().as_secs_f64());
+        sim.display_state();
+    }
+    sim.csv_writer.flush()?;
+    if let Some(ledger) = &options.ledger {
+        sim.ledger.write(Path::new(ledger))?;
+    }
+    if let Some(checkpoint) = &options.checkpoint {
+        sim.save_checkpoint(Path::new(checkpoint))?;
+    }
+    if let (Some(report), Some(model)) = (&options.report, model) {
+        let output = Output::read(Path::new(&options.output))?;
+        let title = options.resume.as_ref().unwrap_or(&options.reson_file);
+        report::write_report(Path::new(report), title, sim, &model, &output)?;
+        if options.verbosity > Verbosity::Quiet {
+            println!("Wrote {}", report);
+        }
+    }
+    Ok(completed)
 }
@@ -0,0 +1,135 @@
+use crate::expr::Expr;
+use crate::resource::Process;
+use crate::simulation::Simulation;
+use chrono::{DateTime, Utc};
+
+/// Custom logic run along a simulation, like alerts, metrics or assertions.
+///
+/// Every callback does nothing by default. Callbacks made in the middle of a tick get the
+/// time instead of the simulation, as its state is only consistent between ticks.
+pub trait Observer {
+    /// Called before every simulated second.
+    fn before_tick(&mut self, _sim: &Simulation) {}
+
+    /// Called after every simulated second, once the time has moved on.
+    fn after_tick(&mut self, _sim: &Simulation) {}
+
+    /// Called when a process is due or runs, with the number of times it can run, 0 when it is
+    /// held back. Demand processes also run to serve waiting orders, and on-use processes are
+    /// reported every second with the amount they can supply.
+    fn on_process(&mut self, _time: DateTime<Utc>, _name: &str, _process: &Process, _can_run: f64) {}
+
+    /// Called when a batch of a resource decays.
+    fn on_decay(&mut self, _time: DateTime<Utc>, _resource_name: &str, _amount: f64) {}
+
+    /// Called after a record is written to the output.
+    fn on_write(&mut self, _sim: &Simulation, _record: &[String]) {}
+}
+
+/// The observers of a simulation, which are not part of its state.
+#[derive(Default)]
+pub struct Observers(Vec<Box<dyn Observer>>);
+
+/// Clones of a simulation start without observers, like they start without an output.
+impl Clone for Observers {
+    fn clone(&self) -> Self {
+        Observers::default()
+    }
+}
+
+impl Observers {
+    pub fn add(&mut self, observer: Box<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn before_tick(&mut self, sim: &Simulation) {
+        for observer in &mut self.0 {
+            observer.before_tick(sim);
+        }
+    }
+
+    pub fn after_tick(&mut self, sim: &Simulation) {
+        for observer in &mut self.0 {
+            observer.after_tick(sim);
+        }
+    }
+
+    pub fn on_process(&mut self, time: DateTime<Utc>, name: &str, process: &Process, can_run: f64) {
+        for observer in &mut self.0 {
+            observer.on_process(time, name, process, can_run);
+        }
+    }
+
+    pub fn on_decay(&mut self, time: DateTime<Utc>, resource_name: &str, amount: f64) {
+        for observer in &mut self.0 {
+            observer.on_decay(time, resource_name, amount);
+        }
+    }
+
+    pub fn on_write(&mut self, sim: &Simulation, record: &[String]) {
+        for observer in &mut self.0 {
+            observer.on_write(sim, record);
+        }
+    }
+}
+
+/// Prints a line whenever a condition on the resource amounts starts to hold.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    spec: String,
+    left: Expr,
+    operator: &'static str,
+    right: Expr,
+    holds: bool,
+}
+
+impl Alert {
+    /// Parses a comparison of two expressions, like `cad < 1000` or `wood + graphite >= 0.9 * 2e6`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        // Two-character operators first, so `<=` isn't read as `<`
+        let (operator, position) = ["<=", ">=", "<", ">"].iter()
+            .find_map(|operator| spec.find(operator).map(|position| (*operator, position)))
+            .ok_or_else(|| format!("Invalid alert {}, expected a comparison like cad<1000", spec))?;
+        Ok(Alert {
+            spec: spec.to_string(),
+            left: Expr::parse(&spec[..position])?,
+            operator,
+            right: Expr::parse(&spec[position + operator.len()..])?,
+            holds: false,
+        })
+    }
+
+    /// Returns an error naming the first variable which isn't a resource of the simulation.
+    pub fn check_names(&self, sim: &Simulation) -> Result<(), String> {
+        match self.left.variables().into_iter().chain(self.right.variables()).find(|name| !sim.resources.contains_key(*name)) {
+            Some(name) => Err(format!("Unknown resource {} in alert {}", name, self.spec)),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self, sim: &Simulation) -> Result<bool, String> {
+        let lookup = |name: &str| sim.resources.get(name).map(|resource| resource.amount);
+        let (left, right) = (self.left.eval(&lookup)?, self.right.eval(&lookup)?);
+        Ok(match self.operator {
+            "<=" => left <= right,
+            ">=" => left >= right,
+            "<" => left < right,
+            _ => left > right,
+        })
+    }
+}
+
+impl Observer for Alert {
+    fn after_tick(&mut self, sim: &Simulation) {
+        // A resource removed by an event makes the condition unknown, which doesn't hold
+        let holds = self.check(sim).unwrap_or(false);
+        if holds && !self.holds {
+            eprintln!("{}: alert {}", sim.time, self.spec);
+        }
+        self.holds = holds;
+    }
+}
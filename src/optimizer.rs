@@ -0,0 +1,368 @@
+use crate::parameter::set_parameter;
+use crate::resource::Model;
+use crate::simulation::Simulation;
+use crate::sweep::{range_values, scenarios, SweepParameter};
+use chrono::prelude::*;
+use rand::prelude::*;
+use std::cmp::Ordering;
+use std::io;
+
+/// A parameter the optimizer may change, within `[min, max]` and optionally snapped to `step`.
+#[derive(Debug, Clone)]
+pub struct SearchParameter {
+    pub path: String,
+    pub min: f64,
+    pub max: f64,
+    pub step: Option<f64>,
+}
+
+impl SearchParameter {
+    /// Parses `path=min:max` or `path=min:max:step`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (path, range) = spec.split_once('=')
+            .ok_or_else(|| format!("Invalid search parameter {}, expected path=min:max[:step]", spec))?;
+        let range: Vec<f64> = range.split(':')
+            .map(|value| value.trim().parse::<f64>().map_err(|_| format!("Invalid value {} in {}", value, spec)))
+            .collect::<Result<_, _>>()?;
+        let parameter = match range.as_slice() {
+            [min, max] => SearchParameter { path: path.trim().to_string(), min: *min, max: *max, step: None },
+            [min, max, step] if *step > 0.0 => SearchParameter { path: path.trim().to_string(), min: *min, max: *max, step: Some(*step) },
+            _ => return Err(format!("Invalid range in {}, expected min:max[:step]", spec)),
+        };
+        if parameter.min > parameter.max {
+            return Err(format!("Minimum is larger than maximum in {}", spec));
+        }
+        Ok(parameter)
+    }
+
+    /// Clamps a value into the range and snaps it to the step if there is one.
+    fn clamp(&self, value: f64) -> f64 {
+        let value = value.clamp(self.min, self.max);
+        match self.step {
+            // The highest step may lie below the maximum
+            Some(step) => self.min + ((value - self.min) / step).round().min(((self.max - self.min) / step).floor()) * step,
+            None => value,
+        }
+    }
+}
+
+/// Which statistic of a resource the objective looks at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Final,
+    Min,
+    Stockout,
+}
+
+/// What to optimize, e.g. `maximize final cad` or `minimize stockout pencil`.
+#[derive(Debug, Clone)]
+pub struct Objective {
+    pub maximize: bool,
+    pub metric: Metric,
+    pub resource: String,
+}
+
+impl Objective {
+    /// Parses `maximize|minimize [final|min|stockout] resource`, separated by spaces or colons.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let tokens: Vec<&str> = spec.split(|c: char| c == ':' || c.is_whitespace()).filter(|t| !t.is_empty()).collect();
+        let (direction, metric, resource) = match tokens.as_slice() {
+            [direction, resource] => (*direction, "final", *resource),
+            [direction, metric, resource] => (*direction, *metric, *resource),
+            _ => return Err(format!("Invalid objective {}, expected maximize|minimize [final|min|stockout] resource", spec)),
+        };
+        let maximize = match direction {
+            "maximize" | "max" => true,
+            "minimize" | "min" => false,
+            _ => return Err(format!("Invalid objective direction {}", direction)),
+        };
+        let metric = match metric {
+            "final" => Metric::Final,
+            "min" => Metric::Min,
+            "stockout" => Metric::Stockout,
+            _ => return Err(format!("Invalid objective metric {}", metric)),
+        };
+        Ok(Objective { maximize, metric, resource: resource.to_string() })
+    }
+}
+
+/// A lower bound the resource must never go below during the run, e.g. `pencil>=100`.
+#[derive(Debug, Clone)]
+pub struct MinConstraint {
+    pub resource: String,
+    pub min: f64,
+}
+
+impl MinConstraint {
+    /// Parses `resource>=value`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (resource, min) = spec.split_once(">=")
+            .ok_or_else(|| format!("Invalid constraint {}, expected resource>=value", spec))?;
+        let min = min.trim().parse().map_err(|_| format!("Invalid value in constraint {}", spec))?;
+        Ok(MinConstraint { resource: resource.trim().to_string(), min })
+    }
+}
+
+/// The search strategy of the optimizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Grid,
+    Random,
+    Evolution,
+}
+
+impl Strategy {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "grid" => Ok(Strategy::Grid),
+            "random" => Ok(Strategy::Random),
+            "evolution" => Ok(Strategy::Evolution),
+            _ => Err(format!("Unknown strategy {}, expected grid, random or evolution", spec)),
+        }
+    }
+}
+
+/// The result of simulating one configuration.
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub values: Vec<f64>,
+    pub objective: f64,
+    /// Sum of how far the constraints were violated, 0 if the configuration is feasible.
+    pub violation: f64,
+}
+
+impl Evaluation {
+    /// Feasible configurations are always better, otherwise the smaller violation wins.
+    fn compare(&self, other: &Evaluation, maximize: bool) -> Ordering {
+        match other.violation.partial_cmp(&self.violation).unwrap_or(Ordering::Equal) {
+            Ordering::Equal if maximize => self.objective.partial_cmp(&other.objective).unwrap_or(Ordering::Equal),
+            Ordering::Equal => other.objective.partial_cmp(&self.objective).unwrap_or(Ordering::Equal),
+            ordering => ordering,
+        }
+    }
+}
+
+/// Searches parameter values for the best configuration of a model.
+pub struct Optimizer {
+    pub model: Model,
+    pub parameters: Vec<SearchParameter>,
+    pub objective: Objective,
+    pub constraints: Vec<MinConstraint>,
+    pub start_time: DateTime<Utc>,
+    pub run_for: u64,
+    /// Doesn't print every evaluated configuration.
+    pub quiet: bool,
+}
+
+impl Optimizer {
+    /// Runs the strategy and returns the best evaluation found.
+    ///
+    /// `iterations` is the number of simulations for random search and evolution, grid
+    /// search simulates every combination of steps instead.
+    pub fn optimize(&self, strategy: Strategy, iterations: usize, seed: u64) -> io::Result<Evaluation> {
+        self.check_names()?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut best: Option<Evaluation> = None;
+        let mut consider = |evaluation: Evaluation| {
+            if !self.quiet {
+                println!(
+                    "{} -> {} {}",
+                    self.describe(&evaluation.values),
+                    evaluation.objective,
+                    if evaluation.violation > 0.0 { format!("(violates constraints by {})", evaluation.violation) } else { String::new() },
+                );
+            }
+            if best.as_ref().is_none_or(|best| evaluation.compare(best, self.objective.maximize) == Ordering::Greater) {
+                best = Some(evaluation);
+            }
+        };
+
+        match strategy {
+            Strategy::Grid => {
+                for values in self.grid()? {
+                    consider(self.evaluate(values)?);
+                }
+            }
+            Strategy::Random => {
+                for _ in 0..iterations {
+                    let values = self.parameters.iter()
+                        .map(|parameter| parameter.clamp(rng.gen_range(parameter.min..=parameter.max)))
+                        .collect();
+                    consider(self.evaluate(values)?);
+                }
+            }
+            Strategy::Evolution => {
+                // (1 + 4) evolution strategy, the mutation strength shrinks as the search goes on
+                let offspring = 4;
+                let mut parent = self.evaluate(self.parameters.iter().map(|parameter| parameter.clamp((parameter.min + parameter.max) / 2.0)).collect())?;
+                consider(parent.clone());
+                let generations = iterations.saturating_sub(1) / offspring;
+                for generation in 0..generations {
+                    let sigma = 0.3 * (1.0 - generation as f64 / generations as f64) + 0.02;
+                    let mut best_child: Option<Evaluation> = None;
+                    for _ in 0..offspring {
+                        let values = self.parameters.iter().zip(&parent.values)
+                            .map(|(parameter, value)| parameter.clamp(value + gaussian(&mut rng) * sigma * (parameter.max - parameter.min)))
+                            .collect();
+                        let child = self.evaluate(values)?;
+                        consider(child.clone());
+                        if best_child.as_ref().is_none_or(|best| child.compare(best, self.objective.maximize) == Ordering::Greater) {
+                            best_child = Some(child);
+                        }
+                    }
+                    let best_child = best_child.unwrap();
+                    if best_child.compare(&parent, self.objective.maximize) != Ordering::Less {
+                        parent = best_child;
+                    }
+                }
+            }
+        }
+
+        best.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No configuration was evaluated"))
+    }
+
+    /// Formats parameter values as `path=value` pairs.
+    pub fn describe(&self, values: &[f64]) -> String {
+        self.parameters.iter().zip(values)
+            .map(|(parameter, value)| format!("{}={}", parameter.path, value))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Simulates the model with the given parameter values.
+    pub fn evaluate(&self, values: Vec<f64>) -> io::Result<Evaluation> {
+        let mut model = self.model.clone();
+        for (parameter, value) in self.parameters.iter().zip(&values) {
+            set_parameter(&mut model.resources, &mut model.processes, &mut model.on_use_processes, &parameter.path, *value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let mut sim = Simulation::new(model, Box::new(io::sink()));
+        sim.set_start_time(self.start_time);
+        sim.write_every = self.run_for.max(1);
+        sim.run(self.run_for);
+
+        let objective = match sim.resources.get(&self.objective.resource) {
+            Some(resource) => match self.objective.metric {
+                Metric::Final => resource.amount,
+                Metric::Min => resource.resource_min_for_run,
+                Metric::Stockout => resource.stockout_seconds as f64,
+            },
+            // Only the final net worth is known, it is not tracked during the run
+            None => sim.net_worth(),
+        };
+        let violation = self.constraints.iter()
+            .map(|constraint| (constraint.min - sim.resources[&constraint.resource].resource_min_for_run).max(0.0))
+            .sum();
+        Ok(Evaluation { values, objective, violation })
+    }
+
+    /// Returns every combination of the parameter steps.
+    fn grid(&self) -> io::Result<Vec<Vec<f64>>> {
+        let parameters = self.parameters.iter()
+            .map(|parameter| {
+                let step = parameter.step.ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Grid search needs a step for {}", parameter.path),
+                ))?;
+                Ok(SweepParameter { path: parameter.path.clone(), values: range_values(parameter.min, parameter.max, step) })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(scenarios(&parameters))
+    }
+
+    /// Checks that the objective and constraints refer to resources of the model, or the objective to its net worth.
+    fn check_names(&self) -> io::Result<()> {
+        let net_worth = self.objective.resource == "net_worth" && !self.model.resources.contains_key("net_worth");
+        if net_worth && self.objective.metric != Metric::Final {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only the final net worth can be optimized"));
+        }
+        let objective = Some(&self.objective.resource).filter(|_| !net_worth);
+        for name in objective.into_iter().chain(self.constraints.iter().map(|constraint| &constraint.resource)) {
+            if !self.model.resources.contains_key(name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown resource {}", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draws from the standard normal distribution using the Box-Muller transform.
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+
+    /// Every run buys `max_catalyst` units of stock for 10 cad each, in its first second.
+    fn optimizer(constraint: &str) -> Optimizer {
+        let model = parse_model("cad\n    resource\n    amount 100\n\nstock\n    resource\n\nbuy\n    process\n    use\n        cad 10\n    produce\n        stock 1\n    period 1 h\n").unwrap();
+        Optimizer {
+            model,
+            parameters: vec![SearchParameter::parse("buy.max_catalyst=0:5:1").unwrap()],
+            objective: Objective::parse("maximize final stock").unwrap(),
+            constraints: vec![MinConstraint::parse(constraint).unwrap()],
+            start_time: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            run_for: 1,
+            quiet: true,
+        }
+    }
+
+    #[test]
+    fn specs() {
+        let parameter = SearchParameter::parse("buy.max_catalyst=0:5:2").unwrap();
+        assert_eq!((parameter.min, parameter.max, parameter.step), (0.0, 5.0, Some(2.0)));
+        assert_eq!(parameter.clamp(3.1), 4.0);
+        assert_eq!(parameter.clamp(9.0), 4.0);
+        assert!(SearchParameter::parse("buy.max_catalyst=5:0").is_err());
+        assert!(SearchParameter::parse("buy.max_catalyst=0:5:0").is_err());
+
+        let objective = Objective::parse("min:stockout:pencil").unwrap();
+        assert!(!objective.maximize);
+        assert_eq!(objective.metric, Metric::Stockout);
+        assert_eq!(Objective::parse("maximize cad").unwrap().metric, Metric::Final);
+        assert!(Objective::parse("increase cad").is_err());
+        assert_eq!(MinConstraint::parse("cad >= 70").unwrap().min, 70.0);
+    }
+
+    #[test]
+    fn feasible_configurations_win() {
+        let objective = |objective, violation| Evaluation { values: Vec::new(), objective, violation };
+        assert_eq!(objective(1.0, 0.0).compare(&objective(5.0, 0.1), true), Ordering::Greater);
+        assert_eq!(objective(5.0, 0.2).compare(&objective(1.0, 0.1), true), Ordering::Less);
+        assert_eq!(objective(1.0, 0.0).compare(&objective(5.0, 0.0), false), Ordering::Greater);
+    }
+
+    #[test]
+    fn every_strategy_finds_the_best_feasible_configuration() {
+        // At most 3 runs keep 70 cad
+        let optimizer = optimizer("cad>=70");
+        for strategy in [Strategy::Grid, Strategy::Random, Strategy::Evolution] {
+            let best = optimizer.optimize(strategy, 41, 7).unwrap();
+            assert_eq!(best.values, [3.0], "{:?}", strategy);
+            assert_eq!(best.objective, 3.0);
+            assert_eq!(best.violation, 0.0);
+        }
+    }
+
+    #[test]
+    fn infeasible_configurations_are_ranked_by_violation() {
+        let best = optimizer("cad>=200").optimize(Strategy::Grid, 0, 0).unwrap();
+        assert_eq!(best.values, [0.0]);
+        assert_eq!(best.violation, 100.0);
+    }
+
+    #[test]
+    fn unknown_resources_and_grids_without_steps_are_rejected() {
+        let mut optimizer = optimizer("wood>=1");
+        assert!(optimizer.optimize(Strategy::Random, 1, 0).is_err());
+        optimizer.constraints.clear();
+        optimizer.parameters = vec![SearchParameter::parse("buy.max_catalyst=0:5").unwrap()];
+        assert!(optimizer.optimize(Strategy::Grid, 1, 0).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::resource::{Demand, Process, Resource};
+use indexmap::IndexMap;
+
+/// Sets a model parameter addressed by a path like `cad.amount`,
+/// `manufacture_pencil.max_catalyst` or `wood_intake.produce.wood`.
+///
+/// Resource fields are `amount`, `max`, `min` and `life`. Process fields are `max_catalyst`,
+/// `period`, `period_delta`, `on_use`, `enabled` (0 or 1), `orders` (demand processes only)
+/// and the quantities in `use.<resource>`, `produce.<resource>` and `catalyze.<resource>`.
+pub fn set_parameter(
+    resources: &mut IndexMap<String, Resource>,
+    processes: &mut IndexMap<String, Process>,
+    on_use_processes: &mut IndexMap<String, Process>,
+    path: &str,
+    value: f64,
+) -> Result<(), String> {
+    let (name, field) = split_path(resources, processes, on_use_processes, path)?;
+
+    if let Some(resource) = resources.get_mut(&name) {
+        match field.as_str() {
+            "amount" => resource.amount = value,
+            "max" => resource.max = value,
+            "min" => resource.min = value,
+            "life" => resource.life = value as u64,
+            _ => return Err(format!("Unknown resource field {} in {}", field, path)),
+        }
+        return Ok(());
+    }
+
+    let process = processes.get_mut(&name).or_else(|| on_use_processes.get_mut(&name)).unwrap();
+    match field.as_str() {
+        "max_catalyst" => process.max_catalyst = value as u64,
+        "period" if value < 1.0 => return Err(format!("Invalid period {} in {}, it must be at least 1 s", value, path)),
+        "period" => process.period = value as u64,
+        "period_delta" => process.period_delta = value as u64,
+        "on_use" => process.on_use = value,
+        "enabled" => process.enabled = value != 0.0,
+        "orders" => demand_mut(process, path)?.orders = value as u64,
+        _ => *process_quantity(process, &name, &field, path)? = value,
+    }
+    Ok(())
+}
+
+/// Returns the current value of a model parameter, see `set_parameter` for the paths.
+pub fn get_parameter(
+    resources: &IndexMap<String, Resource>,
+    processes: &IndexMap<String, Process>,
+    on_use_processes: &IndexMap<String, Process>,
+    path: &str,
+) -> Result<f64, String> {
+    let (name, field) = split_path(resources, processes, on_use_processes, path)?;
+
+    if let Some(resource) = resources.get(&name) {
+        return match field.as_str() {
+            "amount" => Ok(resource.amount),
+            "max" => Ok(resource.max),
+            "min" => Ok(resource.min),
+            "life" => Ok(resource.life as f64),
+            _ => Err(format!("Unknown resource field {} in {}", field, path)),
+        };
+    }
+
+    let process = processes.get(&name).or_else(|| on_use_processes.get(&name)).unwrap();
+    match field.as_str() {
+        "max_catalyst" => Ok(process.max_catalyst as f64),
+        "period" => Ok(process.period as f64),
+        "period_delta" => Ok(process.period_delta as f64),
+        "on_use" => Ok(process.on_use),
+        "enabled" => Ok(if process.enabled { 1.0 } else { 0.0 }),
+        "orders" => Ok(demand(process, path)?.orders as f64),
+        _ => {
+            let (list, resource_name) = split_quantity(field.as_str(), path)?;
+            let quantities = match list {
+                "use" => &process.input,
+                "produce" => &process.output,
+                _ => &process.catalyst,
+            };
+            quantities.get(resource_name).copied()
+                .ok_or_else(|| format!("{} does not {} {}", name, list, resource_name))
+        }
+    }
+}
+
+/// Returns the demand of a process, for the fields only demand processes have.
+fn demand<'a>(process: &'a Process, path: &str) -> Result<&'a Demand, String> {
+    process.demand.as_ref().ok_or_else(|| format!("{} is not a demand process", path))
+}
+
+/// Returns the demand of a process to change it.
+fn demand_mut<'a>(process: &'a mut Process, path: &str) -> Result<&'a mut Demand, String> {
+    process.demand.as_mut().ok_or_else(|| format!("{} is not a demand process", path))
+}
+
+/// Splits a parameter path into the resource or process name and the field.
+fn split_path(
+    resources: &IndexMap<String, Resource>,
+    processes: &IndexMap<String, Process>,
+    on_use_processes: &IndexMap<String, Process>,
+    path: &str,
+) -> Result<(String, String), String> {
+    // Names may contain dots themselves (namespaced templates), so match the longest known name
+    let name = resources.keys()
+        .chain(processes.keys())
+        .chain(on_use_processes.keys())
+        .filter(|name| path.len() > name.len() + 1 && path.starts_with(name.as_str()) && path.as_bytes()[name.len()] == b'.')
+        .max_by_key(|name| name.len())
+        .cloned()
+        .ok_or_else(|| format!("No resource or process matches parameter {}", path))?;
+    let field = path[name.len() + 1..].to_string();
+    Ok((name, field))
+}
+
+/// Splits a quantity field like `use.wood` into the `use`, `produce` or `catalyze` list and the resource name.
+fn split_quantity<'a>(field: &'a str, path: &str) -> Result<(&'a str, &'a str), String> {
+    match field.split_once('.') {
+        Some((list, resource_name)) if matches!(list, "use" | "produce" | "catalyze") => Ok((list, resource_name)),
+        _ => Err(format!("Unknown process field {} in {}", field, path)),
+    }
+}
+
+/// Returns the quantity of a resource in the `use`, `produce` or `catalyze` list of a process to change it.
+fn process_quantity<'a>(process: &'a mut Process, name: &str, field: &str, path: &str) -> Result<&'a mut f64, String> {
+    let (list, resource_name) = split_quantity(field, path)?;
+    let quantities = match list {
+        "use" => &mut process.input,
+        "produce" => &mut process.output,
+        _ => &mut process.catalyst,
+    };
+    quantities.get_mut(resource_name)
+        .ok_or_else(|| format!("{} does not {} {}", name, list, resource_name))
+}
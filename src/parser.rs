@@ -1,23 +1,98 @@
-use crate::resource::{Resource, Process};
+use crate::expr::Expr;
+use crate::resource::{Demand, Event, EventTime, ExchangeRate, Instrument, InstrumentKind, Model, Modification, Resource, Process, Transport, VALUE_VARIABLES};
+use crate::units::convert_process_units;
+use chrono::prelude::*;
 use indexmap::IndexMap;
 use std::fs::File;
 use std::io::{self, BufRead};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::iter::Peekable;
+use std::str::FromStr;
 
-// Function to parse the simulation file and return resources, processes, and on-use processes
-pub fn parse_simulation_file(filename: &str) -> io::Result<(IndexMap<String, Resource>, IndexMap<String, Process>, IndexMap<String, Process>)> {
-    let path = Path::new(filename);
-    let file = File::open(&path)?;
+// Definitions collected while parsing a file, its includes and template instances
+#[derive(Default)]
+struct ParseState {
+    resources: IndexMap<String, Resource>,
+    processes: IndexMap<String, Process>,
+    on_use_processes: IndexMap<String, Process>,
+    events: IndexMap<String, Event>,
+    base_currency: Option<String>,
+    exchange_rates: IndexMap<String, ExchangeRate>,
+    instruments: IndexMap<String, Instrument>,
+    templates: IndexMap<String, Template>,
+    // Templates being instantiated, innermost last, to catch templates instantiating themselves
+    template_stack: Vec<String>,
+    // Lines which were ignored, like unknown fields
+    warnings: Vec<String>,
+}
+
+// A parameterized block of definitions which can be instantiated multiple times
+#[derive(Clone)]
+struct Template {
+    params: Vec<String>,
+    body: Vec<String>,
+    base_dir: PathBuf,
+}
+
+// Function to parse the simulation file and return its resources, processes, on-use processes and events
+pub fn parse_simulation_file(filename: &str) -> io::Result<Model> {
+    let mut state = ParseState::default();
+    let mut include_stack = Vec::new();
+    parse_file(Path::new(filename), &mut state, &mut include_stack)?;
+    finish_model(state)
+}
+
+// Function to parse a model written out in a string, includes are relative to the working directory
+#[cfg(test)]
+pub fn parse_model(contents: &str) -> io::Result<Model> {
+    let mut state = ParseState::default();
+    let lines = contents.lines().map(str::to_string).collect();
+    parse_lines(lines, Path::new("."), &mut state, &mut Vec::new())?;
+    finish_model(state)
+}
+
+// Function to check the parsed definitions and link them into the model
+fn finish_model(mut state: ParseState) -> io::Result<Model> {
+    convert_process_units(&state.resources, &mut state.processes).map_err(invalid_data)?;
+    convert_process_units(&state.resources, &mut state.on_use_processes).map_err(invalid_data)?;
+    check_value_variables(&state)?;
+    check_currencies(&state)?;
+    link_loans(&mut state)?;
+    check_transports(&state)?;
+
+    Ok(Model {
+        resources: state.resources,
+        processes: state.processes,
+        on_use_processes: state.on_use_processes,
+        events: state.events,
+        base_currency: state.base_currency,
+        exchange_rates: state.exchange_rates,
+        instruments: state.instruments,
+        warnings: state.warnings,
+    })
+}
+
+// Function to parse a single file into the state, following its includes
+fn parse_file(path: &Path, state: &mut ParseState, include_stack: &mut Vec<PathBuf>) -> io::Result<()> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let canonical = path.canonicalize()?;
+    if include_stack.contains(&canonical) {
+        return Err(invalid_data(format!("Circular include of {}", path.display())));
+    }
     let reader = io::BufReader::new(file);
 
-    let mut resources = IndexMap::new();
-    let mut processes = IndexMap::new();
-    let mut on_use_processes = IndexMap::new();
-    
     // Collect all lines from the file into a vector so we can process them multiple times
     let lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
+    let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
 
+    include_stack.push(canonical);
+    let result = parse_lines(lines, &base_dir, state, include_stack);
+    include_stack.pop();
+    result
+}
+
+// Function to parse the lines of a file (or an instantiated template) into the state
+fn parse_lines(lines: Vec<String>, base_dir: &Path, state: &mut ParseState, include_stack: &mut Vec<PathBuf>) -> io::Result<()> {
     let mut iter = lines.into_iter().peekable();
 
     let mut name = String::new();
@@ -27,31 +102,371 @@ pub fn parse_simulation_file(filename: &str) -> io::Result<(IndexMap<String, Res
             continue; // Skip empty lines, comments, lines with all whitespaces
         }
         let current_indentation = line.chars().take_while(|&c| c == ' ').count();
-        // Check if it's a resource or process declaration
-        if line.ends_with("resource") {
-            let resource = parse_resource(&mut iter, current_indentation);
-            resources.insert(name.clone(), resource);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        // Check if it's an include, template, instance, resource, process, event or currency declaration
+        if tokens[0] == "include" {
+            parse_include(&tokens, base_dir, state, include_stack)?;
+        } else if tokens[0] == "base_currency" && tokens.len() == 2 {
+            state.base_currency = Some(tokens[1].to_lowercase());
+        } else if tokens[0] == "template" {
+            let template = Template {
+                params: tokens[1..].iter().map(|t| t.to_string()).collect(),
+                body: parse_template_body(&mut iter),
+                base_dir: base_dir.to_path_buf(),
+            };
+            state.templates.insert(name.clone(), template);
+        } else if tokens[0] == "instance" {
+            if tokens.len() != 2 {
+                return Err(invalid_data(format!("Invalid instance declaration for {}: {}", name, line)));
+            }
+            let args = parse_instance_args(&mut iter, current_indentation, &name)?;
+            instantiate_template(&name, tokens[1], &args, state, include_stack)?;
+        } else if line.ends_with("resource") {
+            let (mut resource, locations) = parse_resource(&mut iter, current_indentation, &name, &mut state.warnings)?;
+            if locations.is_empty() {
+                resource.location = name.split_once('@').map(|(_, location)| location.to_string());
+                state.resources.insert(name.clone(), resource);
+            } else {
+                // One resource per location, like pencil@warehouse and pencil@store
+                for location in locations {
+                    let resource = Resource { location: Some(location.clone()), ..resource.clone() };
+                    state.resources.insert(format!("{}@{}", name, location), resource);
+                }
+            }
         } else if line.ends_with("process") {
-            let process = parse_process(&mut iter, current_indentation);
+            let process = parse_process(&mut iter, current_indentation, &name, &mut state.warnings)?;
             if process.on_use > 0.0 {
-                on_use_processes.insert(name.clone(), process);
+                state.on_use_processes.insert(name.clone(), process);
             } else {
-                processes.insert(name.clone(), process);
+                state.processes.insert(name.clone(), process);
             }
+        } else if line == "transport" {
+            let process = parse_transport(&mut iter, current_indentation, &name, &mut state.warnings)?;
+            state.processes.insert(name.clone(), process);
+        } else if line == "demand" {
+            let mut process = parse_process(&mut iter, current_indentation, &name, &mut state.warnings)?;
+            process.demand.get_or_insert_with(Demand::default);
+            state.processes.insert(name.clone(), process);
+        } else if line == "event" {
+            let event = parse_event(&mut iter, current_indentation, &name, &mut state.warnings)?;
+            state.events.insert(name.clone(), event);
+        } else if line == "loan" || line == "savings" {
+            let kind = if line == "loan" { InstrumentKind::Loan } else { InstrumentKind::Savings };
+            let (instrument, balance) = parse_instrument(&mut iter, current_indentation, kind, &name, &mut state.warnings)?;
+            state.resources.insert(name.clone(), balance);
+            state.instruments.insert(name.clone(), instrument);
+        } else if line == "currency" {
+            let exchange_rate = parse_currency(&mut iter, current_indentation, base_dir, &name, &mut state.warnings)?;
+            state.exchange_rates.insert(name.to_lowercase(), exchange_rate);
         } else {
             name = line; // Update the name for the next resource or process
         }
     }
 
-    Ok((resources, processes, on_use_processes))
+    Ok(())
+}
+
+// Function to parse `include path.reson [as namespace]`
+fn parse_include(tokens: &[&str], base_dir: &Path, state: &mut ParseState, include_stack: &mut Vec<PathBuf>) -> io::Result<()> {
+    let path = match tokens {
+        [_, path] | [_, path, "as", _] => base_dir.join(path),
+        _ => return Err(invalid_data(format!("Invalid include: {}", tokens.join(" ")))),
+    };
+    if tokens.len() == 2 {
+        return parse_file(&path, state, include_stack);
+    }
+
+    // Included with a namespace, so parse separately and prefix everything it defines
+    let mut included = ParseState {
+        templates: state.templates.clone(),
+        template_stack: state.template_stack.clone(),
+        ..Default::default()
+    };
+    parse_file(&path, &mut included, include_stack)?;
+    merge_namespaced(included, tokens[3], state);
+    Ok(())
+}
+
+// Function to collect the indented body of a template, dedented to the top level
+fn parse_template_body<I>(iter: &mut Peekable<I>) -> Vec<String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut body = Vec::new();
+    while let Some(line) = iter.peek() {
+        let is_blank = line.trim().is_empty() || line.trim_start().starts_with('#');
+        if !is_blank && !line.starts_with(char::is_whitespace) {
+            break; // Body ends at the next top level line
+        }
+        body.push(iter.next().unwrap());
+    }
+
+    let indentation = body.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(|&c| c == ' ').count())
+        .min()
+        .unwrap_or(0);
+    body.iter()
+        .map(|line| line.chars().skip(indentation).collect())
+        .collect()
+}
+
+// Function to parse the `param value` lines of a template instance
+fn parse_instance_args<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str) -> io::Result<IndexMap<String, String>>
+where
+    I: Iterator<Item = String>,
+{
+    let mut args = IndexMap::new();
+    while let Some(line) = iter.peek() {
+        if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
+            iter.next(); // Skip empty lines, comments, lines with all whitespaces
+            continue;
+        }
+        let line_indentation = line.chars().take_while(|&c| c == ' ').count();
+        if line_indentation <= start_indentation {
+            return Ok(args); // Return if indentation level is less than or equal to start_indentation
+        }
+        let line = iter.next().unwrap().trim().to_string();
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        value(&tokens, name)?;
+        args.insert(tokens[0].to_string(), tokens[1..].join(" "));
+    }
+    Ok(args)
+}
+
+// Function to instantiate a template under the namespace of the instance name
+fn instantiate_template(instance_name: &str, template_name: &str, args: &IndexMap<String, String>, state: &mut ParseState, include_stack: &mut Vec<PathBuf>) -> io::Result<()> {
+    if state.template_stack.iter().any(|name| name == template_name) {
+        return Err(invalid_data(format!("Circular template {} in instance {}", template_name, instance_name)));
+    }
+    let template = state.templates.get(template_name)
+        .ok_or_else(|| invalid_data(format!("Unknown template {} for instance {}", template_name, instance_name)))?
+        .clone();
+    for arg in args.keys() {
+        if !template.params.contains(arg) {
+            return Err(invalid_data(format!("Unknown parameter {} for template {}", arg, template_name)));
+        }
+    }
+
+    // `$name` is the instance name, substituted along with the parameters
+    let mut substitutions = vec![("name".to_string(), instance_name.to_string())];
+    for param in &template.params {
+        let value = args.get(param)
+            .ok_or_else(|| invalid_data(format!("Missing parameter {} for instance {}", param, instance_name)))?;
+        substitutions.push((param.clone(), value.clone()));
+    }
+    // Longest names are substituted first so `$rate` doesn't clobber `$rate_max`
+    substitutions.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+    let lines: Vec<String> = template.body.iter()
+        .map(|line| substitutions.iter().fold(line.clone(), |line, (param, value)| line.replace(&format!("${}", param), value)))
+        .collect();
+
+    let mut template_stack = state.template_stack.clone();
+    template_stack.push(template_name.to_string());
+    let mut instance = ParseState {
+        templates: state.templates.clone(),
+        template_stack,
+        ..Default::default()
+    };
+    parse_lines(lines, &template.base_dir, &mut instance, include_stack)?;
+    merge_namespaced(instance, instance_name, state);
+    Ok(())
+}
+
+// Function to merge definitions into the state, prefixing their names with `namespace.`.
+// References to names which are not defined in `defined` are left untouched, so
+// templates and namespaced includes can still use shared resources such as money.
+fn merge_namespaced(defined: ParseState, namespace: &str, state: &mut ParseState) {
+    let local: Vec<String> = defined.resources.keys()
+        .chain(defined.processes.keys())
+        .chain(defined.on_use_processes.keys())
+        .cloned()
+        .collect();
+    let rename = |name: &String| -> String {
+        if local.contains(name) {
+            format!("{}.{}", namespace, name)
+        } else {
+            name.clone()
+        }
+    };
+    let rename_list = |list: IndexMap<String, f64>| -> IndexMap<String, f64> {
+        list.into_iter().map(|(name, amount)| (rename(&name), amount)).collect()
+    };
+    let rename_process = |mut process: Process| -> Process {
+        process.input = rename_list(process.input);
+        process.output = rename_list(process.output);
+        process.catalyst = rename_list(process.catalyst);
+        process.overdraft = process.overdraft.map(|name| rename(&name));
+        if let Some(transport) = process.transport.as_mut() {
+            // Goods are named without their location, so they follow the resources at the origin
+            transport.goods = std::mem::take(&mut transport.goods).into_iter()
+                .map(|(good, amount)| {
+                    let stored = rename(&format!("{}@{}", good, transport.from));
+                    let good = stored.strip_suffix(&format!("@{}", transport.from)).unwrap().to_string();
+                    (good, amount)
+                })
+                .collect();
+        }
+        if let Some(demand) = process.demand.as_mut() {
+            demand.penalty = rename_list(std::mem::take(&mut demand.penalty));
+        }
+        process.units = process.units.into_iter()
+            .map(|(key, unit)| {
+                let (list, name) = key.split_once('.').unwrap();
+                (format!("{}.{}", list, rename(&name.to_string())), unit)
+            })
+            .collect();
+        process
+    };
+
+    // Currencies and templates are shared by everyone, so they are not namespaced
+    state.templates.extend(defined.templates);
+    if defined.base_currency.is_some() {
+        state.base_currency = defined.base_currency;
+    }
+    state.exchange_rates.extend(defined.exchange_rates);
+    state.warnings.extend(defined.warnings);
+
+    for (name, mut instrument) in defined.instruments {
+        instrument.account = instrument.account.map(|account| rename(&account));
+        state.instruments.insert(rename(&name), instrument);
+    }
+
+    for (name, mut resource) in defined.resources {
+        resource.value = resource.value.map(|value| value.rename_variables(&|variable| rename(&variable.to_string())));
+        state.resources.insert(rename(&name), resource);
+    }
+    for (name, process) in defined.processes {
+        state.processes.insert(rename(&name), rename_process(process));
+    }
+    for (name, process) in defined.on_use_processes {
+        state.on_use_processes.insert(rename(&name), rename_process(process));
+    }
+
+    // Parameter paths start with the name of a resource or process
+    let rename_path = |path: String| -> String {
+        match local.iter().filter(|name| path.starts_with(&format!("{}.", name))).max_by_key(|name| name.len()) {
+            Some(_) => format!("{}.{}", namespace, path),
+            None => path,
+        }
+    };
+    for (name, mut event) in defined.events {
+        event.modifications = event.modifications.into_iter()
+            .map(|modification| match modification {
+                Modification::AddAmount { resource, amount } => Modification::AddAmount { resource: rename(&resource), amount },
+                Modification::SetParameter { path, value } => Modification::SetParameter { path: rename_path(path), value },
+                Modification::ScaleParameter { path, factor } => Modification::ScaleParameter { path: rename_path(path), factor },
+                Modification::AddProcess { name, process } => Modification::AddProcess { name: rename(&name), process: Box::new(rename_process(*process)) },
+                Modification::RemoveProcess { name } => Modification::RemoveProcess { name: rename(&name) },
+                Modification::AddResource { name, resource } => Modification::AddResource { name: rename(&name), resource },
+                Modification::RemoveResource { name } => Modification::RemoveResource { name: rename(&name) },
+                Modification::EnableProcess { name } => Modification::EnableProcess { name: rename(&name) },
+                Modification::DisableProcess { name } => Modification::DisableProcess { name: rename(&name) },
+            })
+            .collect();
+        state.events.insert(format!("{}.{}", namespace, name), event);
+    }
+}
+
+// Function to check that the goods of every transport are stored at both of its locations
+fn check_transports(state: &ParseState) -> io::Result<()> {
+    for (name, process) in &state.processes {
+        let Some(transport) = &process.transport else { continue };
+        for good in transport.goods.keys() {
+            for location in [&transport.from, &transport.to] {
+                if !state.resources.contains_key(&format!("{}@{}", good, location)) {
+                    return Err(invalid_data(format!("Transport {} moves {} but there is no {}@{}", name, good, good, location)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Function to check that currencies have a base currency and conversions have rates for both sides
+fn check_currencies(state: &ParseState) -> io::Result<()> {
+    let base_currency = state.base_currency.as_deref();
+    if let (Some(currency), None) = (state.exchange_rates.keys().next(), base_currency) {
+        return Err(invalid_data(format!("Currency {} needs a base_currency its rate is given in", currency)));
+    }
+    for (name, process) in state.processes.iter().filter(|(_, process)| process.convert) {
+        if base_currency.is_none() {
+            return Err(invalid_data(format!("Convert process {} needs a base_currency", name)));
+        }
+        for resource_name in process.input.keys().chain(process.output.keys()) {
+            let resource = state.resources.get(resource_name)
+                .ok_or_else(|| invalid_data(format!("Convert process {} uses unknown resource {}", name, resource_name)))?;
+            let unit = resource.unit.to_lowercase();
+            if base_currency != Some(unit.as_str()) && !state.exchange_rates.contains_key(&unit) {
+                return Err(invalid_data(format!("Convert process {} exchanges {}, but there is no currency {} with a rate", name, resource_name, unit)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Function to give the balance of every loan the unit of the account it is paid into
+fn link_loans(state: &mut ParseState) -> io::Result<()> {
+    for (name, instrument) in &state.instruments {
+        if instrument.kind != InstrumentKind::Loan {
+            continue;
+        }
+        let account = instrument.account.as_ref()
+            .ok_or_else(|| invalid_data(format!("Loan {} needs an account", name)))?;
+        let unit = state.resources.get(account)
+            .ok_or_else(|| invalid_data(format!("Unknown account {} for loan {}", account, name)))?
+            .unit.clone();
+        state.resources.get_mut(name).unwrap().unit = unit;
+    }
+    Ok(())
+}
+
+// Function to check that the value expressions of resources only use known variables
+fn check_value_variables(state: &ParseState) -> io::Result<()> {
+    for (name, resource) in &state.resources {
+        let Some(value) = &resource.value else { continue };
+        for variable in value.variables() {
+            let known = VALUE_VARIABLES.contains(&variable)
+                || state.resources.contains_key(variable)
+                || state.exchange_rates.contains_key(variable)
+                || state.base_currency.as_deref() == Some(variable);
+            if !known {
+                return Err(invalid_data(format!("Unknown variable {} in value of {}", variable, name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
 }
 
-// Function to parse a resource from the file
-fn parse_resource<I>(iter: &mut Peekable<I>, start_indentation: usize) -> Resource
+// Function to describe a field which isn't known. It is ignored rather than an error, so models
+// with fields of other versions still load
+fn unknown_token(token: &str, name: &str) -> String {
+    format!("Unknown token {} in {}, ignored", token, name)
+}
+
+// Function to get the value of a `field value` line
+fn value<'a>(tokens: &[&'a str], name: &str) -> io::Result<&'a str> {
+    tokens.get(1).copied().ok_or_else(|| invalid_data(format!("Missing value for {} in {}", tokens[0], name)))
+}
+
+// Function to parse the value of a `field value` line
+fn parse_number<T: FromStr>(tokens: &[&str], name: &str) -> io::Result<T> {
+    value(tokens, name)?
+        .parse()
+        .map_err(|_| invalid_data(format!("Invalid {} {} in {}", tokens[0], tokens[1..].join(" "), name)))
+}
+
+// Function to parse a resource from the file along with the locations it is stored at, if any
+fn parse_resource<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str, warnings: &mut Vec<String>) -> io::Result<(Resource, Vec<String>)>
 where
     I: Iterator<Item = String>,
 {
     let mut resource = Resource::default(); // Default values for resource
+    let mut locations = Vec::new();
 
     while let Some(line) = iter.peek() {
         if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
@@ -60,7 +475,7 @@ where
         }
         let line_indentation = line.chars().take_while(|&c| c == ' ').count();
         if line_indentation <= start_indentation {
-            return resource; // Return the resource if indentation level is less than or equal to start_indentation
+            return Ok((resource, locations)); // Return the resource if indentation level is less than or equal to start_indentation
         }
         let line = iter.next().unwrap().trim().to_string();
     
@@ -68,28 +483,43 @@ where
 
         match tokens[0] {
             "unit" => {
-                resource.unit = tokens[1].to_string();
+                resource.unit = value(&tokens, name)?.to_string();
             }
             "max" => {
-                resource.max = tokens[1].parse().unwrap();
+                resource.max = parse_number(&tokens, name)?;
             }
             "life" => {
-                resource.life = parse_time_string(tokens[1], tokens[2]);
+                resource.life = parse_time_string(&tokens, name)?;
             }
             "amount" => {
-                resource.amount = tokens[1].parse().unwrap(); // Default or initial amount, if specified
+                resource.amount = parse_number(&tokens, name)?; // Default or initial amount, if specified
+            }
+            "min" => {
+                resource.min = parse_number(&tokens, name)?;
+            }
+            "locations" => {
+                locations = tokens[1..].iter().map(|location| location.to_string()).collect();
+            }
+            "debt" => {
+                resource.min = f64::MIN; // No lower bound at all
+            }
+            "value" => {
+                // Value of one unit, constant or expression
+                let value = Expr::parse(&tokens[1..].join(" "))
+                    .map_err(|e| invalid_data(format!("Invalid value of {}: {}", name, e)))?;
+                resource.value = Some(value);
             }
             _ => {
-                println!("Unknown token: {}", tokens[0]);
+                warnings.push(unknown_token(tokens[0], name));
             }
         }
     }
 
-    resource
+    Ok((resource, locations))
 }
 
 // Function to parse a process from the file
-fn parse_process<I>(iter: &mut Peekable<I>, start_indentation: usize) -> Process
+fn parse_process<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str, warnings: &mut Vec<String>) -> io::Result<Process>
 where
     I: Iterator<Item = String>,
 {
@@ -102,7 +532,7 @@ where
         }
         let line_indentation = line.chars().take_while(|&c| c == ' ').count();
         if line_indentation <= start_indentation {
-            return process; // Return the process if indentation level is less than or equal to start_indentation
+            return Ok(process); // Return the process if indentation level is less than or equal to start_indentation
         }
         let line = iter.next().unwrap().trim().to_string();
         
@@ -110,49 +540,125 @@ where
 
         match tokens[0] {
             "produce" => {
-                parse_resource_list(&mut *iter, line_indentation, &mut process.output);
+                parse_resource_list(&mut *iter, line_indentation, &mut process.output, Some(("produce", &mut process.units)), name)?;
             }
             "use" => {
-                parse_resource_list(&mut *iter, line_indentation, &mut process.input);
+                parse_resource_list(&mut *iter, line_indentation, &mut process.input, Some(("use", &mut process.units)), name)?;
             }
             "catalyze" => {
                 if tokens.len() > 1 {
-                    process.max_catalyst = tokens[1].parse().unwrap();
+                    process.max_catalyst = parse_number(&tokens, name)?;
                 }
-                parse_resource_list(&mut *iter, line_indentation, &mut process.catalyst);
+                parse_resource_list(&mut *iter, line_indentation, &mut process.catalyst, Some(("catalyze", &mut process.units)), name)?;
             }
             "period" => {
-                process.period = parse_time_string(tokens[1], tokens[2]);
+                process.period = parse_time_string(&tokens, name)?;
+                if process.period == 0 {
+                    return Err(invalid_data(format!("Invalid period 0 in {}, it must be at least 1 s", name)));
+                }
             }
             "period_delta" => {
-                process.period_delta = parse_time_string(tokens[1], tokens[2]);
+                process.period_delta = parse_time_string(&tokens, name)?;
                 if process.period == 604800 {
                     process.period_delta += 4 * 86400;
                     process.period_delta %= 604800;
                 }
             }
             "constraint" => {
-                let (constraint, constraint_modulo) = parse_constraint(&mut *iter, line_indentation);
+                let (constraint, constraint_modulo) = parse_constraint(&mut *iter, line_indentation, name)?;
                 process.constraint = constraint;
                 process.constraint_modulo = constraint_modulo;
             }
             "on_use" => {
-                process.on_use = tokens[1].parse().unwrap();
+                process.on_use = parse_number(&tokens, name)?;
+            }
+            "convert" => {
+                // The amount produced follows the exchange rates while simulating
+                let rate = match tokens[..] {
+                    [_, _, _, rate] => rate.parse().ok(),
+                    _ => None,
+                };
+                let rate = rate.ok_or_else(|| invalid_data(format!("Invalid conversion {} in {}", tokens[1..].join(" "), name)))?;
+                process.input.insert(tokens[1].to_string(), rate);
+                process.output.insert(tokens[2].to_string(), 0.0);
+                process.convert = true;
+            }
+            "orders" => {
+                process.demand.get_or_insert_with(Demand::default).orders = parse_number(&tokens, name)?;
+            }
+            "expire" => {
+                process.demand.get_or_insert_with(Demand::default).expire = Some(parse_time_string(&tokens, name)?);
+            }
+            "penalty" => {
+                let demand = process.demand.get_or_insert_with(Demand::default);
+                parse_resource_list(&mut *iter, line_indentation, &mut demand.penalty, None, name)?;
+            }
+            "from" => {
+                process.transport.get_or_insert_with(Transport::default).from = value(&tokens, name)?.to_string();
+            }
+            "to" => {
+                process.transport.get_or_insert_with(Transport::default).to = value(&tokens, name)?.to_string();
+            }
+            "move" => {
+                let transport = process.transport.get_or_insert_with(Transport::default);
+                parse_resource_list(&mut *iter, line_indentation, &mut transport.goods, None, name)?;
+            }
+            "lead_time" => {
+                process.lead_time = parse_time_string(&tokens, name)?;
+            }
+            "capacity" => {
+                process.capacity = Some(parse_number(&tokens, name)?);
+            }
+            "overdraft" => {
+                process.overdraft = Some(value(&tokens, name)?.to_string());
+            }
+            "enabled" => {
+                process.enabled = parse_number(&tokens, name)?;
+            }
+            "start" => {
+                let value = value(&tokens, name)?;
+                process.start = Some(parse_timestamp(value)
+                    .ok_or_else(|| invalid_data(format!("Invalid start {} for {}", value, name)))?);
+            }
+            "end" => {
+                let value = value(&tokens, name)?;
+                process.end = Some(parse_timestamp(value)
+                    .ok_or_else(|| invalid_data(format!("Invalid end {} for {}", value, name)))?);
             }
             _ => {
-                println!("Unknown token: {}", tokens[0]);
+                warnings.push(unknown_token(tokens[0], name));
             }
         }
     }
 
-    process
+    Ok(process)
+}
+
+// Function to parse a transport, which takes its goods at one location and delivers them at another
+fn parse_transport<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str, warnings: &mut Vec<String>) -> io::Result<Process>
+where
+    I: Iterator<Item = String>,
+{
+    let mut process = parse_process(iter, start_indentation, name, warnings)?;
+    let transport = process.transport.get_or_insert_with(Transport::default).clone();
+    if transport.from.is_empty() || transport.to.is_empty() {
+        return Err(invalid_data(format!("Transport {} needs `from` and `to` locations", name)));
+    }
+    for (good, amount) in &transport.goods {
+        process.input.insert(format!("{}@{}", good, transport.from), *amount);
+        process.output.insert(format!("{}@{}", good, transport.to), *amount);
+    }
+    Ok(process)
 }
 
-// Function to parse a list of resources
-fn parse_resource_list<I>(iter: &mut Peekable<I>, start_indentation: usize, indexmap_to_add: &mut IndexMap<String, f64>)
+// Function to parse an event from the file
+fn parse_event<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str, warnings: &mut Vec<String>) -> io::Result<Event>
 where
     I: Iterator<Item = String>,
 {
+    let mut time = None;
+    let mut modifications = Vec::new();
+
     while let Some(line) = iter.peek() {
         if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
             iter.next(); // Skip empty lines, comments, lines with all whitespaces
@@ -160,41 +666,79 @@ where
         }
         let line_indentation = line.chars().take_while(|&c| c == ' ').count();
         if line_indentation <= start_indentation {
-            return; // Return if indentation level is less than or equal to start_indentation
+            break; // Stop if indentation level is less than or equal to start_indentation
         }
         let line = iter.next().unwrap().trim().to_string();
+
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        if tokens.len() == 2 {
-            let key = tokens[0].to_string();
-            let value: f64 = tokens[1].parse().unwrap();
-            indexmap_to_add.insert(key, value);
-        } else {
-            println!("Invalid resource list entry: {}", line);
+
+        match tokens[0] {
+            "at" => {
+                let value = value(&tokens, name)?;
+                let timestamp = parse_timestamp(value)
+                    .ok_or_else(|| invalid_data(format!("Invalid time {} for event {}", value, name)))?;
+                time = Some(EventTime::At(timestamp));
+            }
+            "after" => {
+                time = Some(EventTime::After(parse_time_string(&tokens, name)?));
+            }
+            "add" => {
+                let mut amounts = IndexMap::new();
+                parse_resource_list(&mut *iter, line_indentation, &mut amounts, None, name)?;
+                for (resource, amount) in amounts {
+                    modifications.push(Modification::AddAmount { resource, amount });
+                }
+            }
+            "set" => {
+                let mut values = IndexMap::new();
+                parse_resource_list(&mut *iter, line_indentation, &mut values, None, name)?;
+                for (path, value) in values {
+                    modifications.push(Modification::SetParameter { path, value });
+                }
+            }
+            "scale" => {
+                let mut factors = IndexMap::new();
+                parse_resource_list(&mut *iter, line_indentation, &mut factors, None, name)?;
+                for (path, factor) in factors {
+                    modifications.push(Modification::ScaleParameter { path, factor });
+                }
+            }
+            "enable" => {
+                for process in &tokens[1..] {
+                    modifications.push(Modification::EnableProcess { name: process.to_string() });
+                }
+            }
+            "disable" => {
+                for process in &tokens[1..] {
+                    modifications.push(Modification::DisableProcess { name: process.to_string() });
+                }
+            }
+            "remove" => {
+                for process in &tokens[1..] {
+                    modifications.push(Modification::RemoveProcess { name: process.to_string() });
+                }
+            }
+            _ => {
+                warnings.push(unknown_token(tokens[0], name));
+            }
         }
     }
-}
 
-// Function to parse a time string into seconds
-fn parse_time_string(num: &str, period: &str) -> u64 {
-    let num: u64 = num.parse().unwrap();
-    match period {
-        "s" => num,
-        "m" => num * 60,
-        "h" => num * 3600,
-        "d" => num * 86400,
-        "w" => num * 604800,
-        "y" => num * 31557600,
-        _ => panic!("Invalid time string {}", period),
-    }
+    let time = time.ok_or_else(|| invalid_data(format!("Event {} needs `at` or `after`", name)))?;
+    Ok(Event { time, modifications })
 }
 
-// Function to parse constraints
-fn parse_constraint<I>(iter: &mut Peekable<I>, start_indentation: usize) -> (Vec<Vec<[u64; 2]>>, Vec<u64>)
+// Function to parse a loan or savings account along with the resource holding its balance
+fn parse_instrument<I>(iter: &mut Peekable<I>, start_indentation: usize, kind: InstrumentKind, name: &str, warnings: &mut Vec<String>) -> io::Result<(Instrument, Resource)>
 where
     I: Iterator<Item = String>,
 {
-    let mut constraint: Vec<Vec<[u64; 2]>> = Vec::new();
-    let mut constraint_modulo: Vec<u64> = Vec::new();
+    let mut instrument = Instrument { kind, ..Instrument::default() };
+    let mut balance = Resource::default();
+    if kind == InstrumentKind::Loan {
+        balance.min = f64::MIN;
+    }
+
     while let Some(line) = iter.peek() {
         if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
             iter.next(); // Skip empty lines, comments, lines with all whitespaces
@@ -202,50 +746,408 @@ where
         }
         let line_indentation = line.chars().take_while(|&c| c == ' ').count();
         if line_indentation <= start_indentation {
-            return (constraint, constraint_modulo); // Return if indentation level is less than or equal to start_indentation
+            break; // Stop if indentation level is less than or equal to start_indentation
         }
         let line = iter.next().unwrap().trim().to_string();
+
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        let mut ranges: Vec<[u64; 2]> = Vec::new();
-        let mut multiplier = 0;
-        let mut delta = 0;
+
         match tokens[0] {
-            "s" => {
-                constraint_modulo.push(60);
-                multiplier = 1;
+            "account" => {
+                instrument.account = Some(value(&tokens, name)?.to_string());
+            }
+            "principal" => {
+                instrument.principal = parse_number(&tokens, name)?;
             }
-            "m" => {
-                constraint_modulo.push(3600);
-                multiplier = 60;
+            "interest" => {
+                let interest: f64 = parse_number(&tokens, name)?;
+                // Rates are fractions unless written as percentages
+                instrument.interest = if tokens.get(2) == Some(&"%") { interest / 100.0 } else { interest };
             }
-            "h" => {
-                constraint_modulo.push(86400);
-                multiplier = 3600;
+            "term" => {
+                instrument.term = parse_time_string(&tokens, name)?;
+            }
+            "period" => {
+                instrument.period = parse_time_string(&tokens, name)?;
+                if instrument.period == 0 {
+                    return Err(invalid_data(format!("Invalid period 0 in {}, it must be at least 1 s", name)));
+                }
+            }
+            "period_delta" => {
+                instrument.period_delta = parse_time_string(&tokens, name)?;
+            }
+            "start" => {
+                let value = value(&tokens, name)?;
+                instrument.start = Some(parse_timestamp(value)
+                    .ok_or_else(|| invalid_data(format!("Invalid start {} for {}", value, name)))?);
+            }
+            "unit" => {
+                balance.unit = value(&tokens, name)?.to_string();
+            }
+            "amount" => {
+                balance.amount = parse_number(&tokens, name)?;
+            }
+            _ => {
+                warnings.push(unknown_token(tokens[0], name));
+            }
+        }
+    }
+
+    if kind == InstrumentKind::Loan && instrument.term < instrument.period {
+        return Err(invalid_data(format!("Loan {} needs a term of at least one period", name)));
+    }
+    Ok((instrument, balance))
+}
+
+// Function to parse a currency's exchange rate into the base currency from the file
+fn parse_currency<I>(iter: &mut Peekable<I>, start_indentation: usize, base_dir: &Path, name: &str, warnings: &mut Vec<String>) -> io::Result<ExchangeRate>
+where
+    I: Iterator<Item = String>,
+{
+    let mut exchange_rate = ExchangeRate::default();
+
+    while let Some(line) = iter.peek() {
+        if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
+            iter.next(); // Skip empty lines, comments, lines with all whitespaces
+            continue;
+        }
+        let line_indentation = line.chars().take_while(|&c| c == ' ').count();
+        if line_indentation <= start_indentation {
+            break; // Stop if indentation level is less than or equal to start_indentation
+        }
+        let line = iter.next().unwrap().trim().to_string();
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens[0] {
+            "rate" => {
+                exchange_rate.rate = parse_number(&tokens, name)?;
             }
-            "w" => {
-                constraint_modulo.push(604800);
-                multiplier = 86400;
-                // Jan 1 1970 was a Thursday
-                delta = 3;
+            "rate_file" => {
+                exchange_rate.series = parse_rate_file(&base_dir.join(value(&tokens, name)?))?;
             }
             _ => {
-                println!("Unknown token: {}", tokens[0]);
+                warnings.push(unknown_token(tokens[0], name));
             }
         }
-        for i in 1..tokens.len() {
+    }
+
+    Ok(exchange_rate)
+}
+
+// Function to parse a CSV file of `time,rate` rows, with the time as a date, RFC3339 timestamp or seconds since the epoch
+fn parse_rate_file(path: &Path) -> io::Result<Vec<(u64, f64)>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut series = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let (time, rate) = (record.get(0).unwrap_or("").trim(), record.get(1).unwrap_or("").trim());
+        let time = time.parse().ok().or_else(|| parse_timestamp(time))
+            .ok_or_else(|| invalid_data(format!("Invalid time {} in {}", time, path.display())))?;
+        let rate = rate.parse()
+            .map_err(|_| invalid_data(format!("Invalid rate {} in {}", rate, path.display())))?;
+        series.push((time, rate));
+    }
+    series.sort_by_key(|(time, _)| *time);
+    Ok(series)
+}
+
+// Function to parse a date (2024-03-01), a date and time in UTC (2024-03-01 09:00:00 or
+// 2024-03-01T09:00:00) or RFC3339 timestamp (2024-03-01T09:00:00Z) into seconds since the epoch
+pub fn parse_timestamp(value: &str) -> Option<u64> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp() as u64);
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(time.and_utc().timestamp() as u64);
+        }
+    }
+    DateTime::parse_from_rfc3339(value).ok().map(|time| time.timestamp() as u64)
+}
+
+// Function to parse a list of resources. If `units` is given, entries may have a unit
+// (`wood 10 kg`) which is recorded under `list.resource` for the conversion after parsing.
+fn parse_resource_list<I>(iter: &mut Peekable<I>, start_indentation: usize, indexmap_to_add: &mut IndexMap<String, f64>, mut units: Option<(&str, &mut IndexMap<String, String>)>, name: &str) -> io::Result<()>
+where
+    I: Iterator<Item = String>,
+{
+    while let Some(line) = iter.peek() {
+        if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
+            iter.next(); // Skip empty lines, comments, lines with all whitespaces
+            continue;
+        }
+        let line_indentation = line.chars().take_while(|&c| c == ' ').count();
+        if line_indentation <= start_indentation {
+            return Ok(()); // Return if indentation level is less than or equal to start_indentation
+        }
+        let line = iter.next().unwrap().trim().to_string();
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let value = tokens.get(1).and_then(|value| value.parse::<f64>().ok());
+        match value {
+            Some(value) if tokens.len() == 2 || (tokens.len() == 3 && units.is_some()) => {
+                let key = tokens[0].to_string();
+                if let (Some(unit), Some((list, units))) = (tokens.get(2), units.as_mut()) {
+                    units.insert(format!("{}.{}", list, key), unit.to_string());
+                }
+                indexmap_to_add.insert(key, value);
+            }
+            _ => return Err(invalid_data(format!("Invalid entry {} in {}", line, name))),
+        }
+    }
+    Ok(())
+}
+
+// Function to get the seconds of a time unit, a month being a twelfth of a year
+fn time_unit_seconds(unit: &str) -> Option<u64> {
+    match unit {
+        "s" => Some(1),
+        "m" => Some(60),
+        "h" => Some(3600),
+        "d" => Some(86400),
+        "w" => Some(604800),
+        "mo" => Some(2629800),
+        "y" => Some(31557600),
+        _ => None,
+    }
+}
+
+// Function to parse the time of a `field number unit` line into seconds
+fn parse_time_string(tokens: &[&str], name: &str) -> io::Result<u64> {
+    let seconds = match tokens[1..] {
+        [num, unit] => num.parse::<u64>().ok().zip(time_unit_seconds(unit)).and_then(|(num, unit)| num.checked_mul(unit)),
+        _ => None,
+    };
+    seconds.ok_or_else(|| invalid_data(format!("Invalid {} {} in {}", tokens[0], tokens[1..].join(" "), name)))
+}
+
+// Function to parse a duration like `90` (seconds), `30d`, `6mo` or `1y2w`, with the units of `parse_time_string`
+pub fn parse_duration(value: &str) -> Option<u64> {
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    let mut rest = value;
+    let mut seconds = 0;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (num, after_num) = rest.split_at(split);
+        let split = after_num.find(|c: char| c.is_ascii_digit()).unwrap_or(after_num.len());
+        let (unit, after_unit) = after_num.split_at(split);
+        seconds = num.parse::<u64>().ok()?.checked_mul(time_unit_seconds(unit)?)?.checked_add(seconds)?;
+        rest = after_unit;
+    }
+    (!value.is_empty()).then_some(seconds)
+}
+
+// Ranges of every constraint line along with the period they repeat in
+type Constraints = (Vec<Vec<[u64; 2]>>, Vec<u64>);
+
+// Function to parse constraints
+fn parse_constraint<I>(iter: &mut Peekable<I>, start_indentation: usize, name: &str) -> io::Result<Constraints>
+where
+    I: Iterator<Item = String>,
+{
+    let mut constraint: Vec<Vec<[u64; 2]>> = Vec::new();
+    let mut constraint_modulo: Vec<u64> = Vec::new();
+    while let Some(line) = iter.peek() {
+        if line.is_empty() || line.starts_with('#') || line.chars().all(char::is_whitespace) {
+            iter.next(); // Skip empty lines, comments, lines with all whitespaces
+            continue;
+        }
+        let line_indentation = line.chars().take_while(|&c| c == ' ').count();
+        if line_indentation <= start_indentation {
+            return Ok((constraint, constraint_modulo)); // Return if indentation level is less than or equal to start_indentation
+        }
+        let line = iter.next().unwrap().trim().to_string();
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let mut ranges: Vec<[u64; 2]> = Vec::new();
+        let (modulo, multiplier, delta) = match tokens[0] {
+            "s" => (60, 1, 0),
+            "m" => (3600, 60, 0),
+            "h" => (86400, 3600, 0),
+            // Jan 1 1970 was a Thursday
+            "w" => (604800, 86400, 3),
+            _ => return Err(invalid_data(format!("Invalid constraint {} in {}", line, name))),
+        };
+        constraint_modulo.push(modulo);
+        for token in &tokens[1..] {
             let mut range: [u64; 2] = [0, 0];
-            let range_tokens: Vec<&str> = tokens[i].split('-').collect();
-            range[0] = (range_tokens[0].parse::<u64>().unwrap() + delta) * multiplier;
-            if range_tokens.len() == 1 {
-                range[1] = range[0] + multiplier - 1;
-            } else {
-                range[1] = ((range_tokens[1].parse::<u64>().unwrap() + 1) + delta) * multiplier - 1;
+            let invalid_range = || invalid_data(format!("Invalid constraint {} in {}", line, name));
+            let range_tokens: Vec<u64> = token.split('-').map(|t| t.parse().map_err(|_| invalid_range())).collect::<io::Result<_>>()?;
+            range[0] = (range_tokens[0] + delta) * multiplier;
+            match range_tokens[..] {
+                [_] => range[1] = range[0] + multiplier - 1,
+                [_, end] => range[1] = ((end + 1) + delta) * multiplier - 1,
+                _ => return Err(invalid_range()),
             }
-            range[0] %= constraint_modulo.last().unwrap();
-            range[1] %= constraint_modulo.last().unwrap();
+            range[0] %= modulo;
+            range[1] %= modulo;
             ranges.push(range);
         }
         constraint.push(ranges);
     }
-    (constraint, constraint_modulo)
-}
\ No newline at end of file
+    Ok((constraint, constraint_modulo))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Writes the files into a fresh directory and returns its path
+    fn write_files(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("resim_parser_{}_{}", test_name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn include_and_template_instances() {
+        let model = parse_simulation_file("example/pencil_lines.reson").unwrap();
+        assert!(model.resources.contains_key("cad"));
+        assert!(model.on_use_processes.contains_key("electric_intake"));
+        assert_eq!(model.resources["line_a.pencil_machine"].max, 2.0);
+        assert_eq!(model.resources["line_c.pencil_machine"].max, 3.0);
+
+        let manufacture = &model.processes["line_b.manufacture_pencil"];
+        assert_eq!(manufacture.output["line_b.pencil"], 3.0);
+        assert_eq!(manufacture.catalyst["line_b.pencil_machine"], 1.0);
+        // Shared resources are not namespaced
+        assert!(manufacture.input.contains_key("wood"));
+        assert!(manufacture.input.contains_key("electric_intake"));
+    }
+
+    #[test]
+    fn templates_of_namespaced_includes_are_visible() {
+        let dir = write_files("namespaced_templates", &[
+            ("main.reson", "include shared.reson as shared\n\nline\n    instance stock\n    size 7\n"),
+            ("shared.reson", "stock\n    template size\n    box\n        resource\n        unit count\n        max $size\n"),
+        ]);
+        let model = parse_simulation_file(dir.join("main.reson").to_str().unwrap()).unwrap();
+        assert_eq!(model.resources["line.box"].max, 7.0);
+    }
+
+    #[test]
+    fn parameters_starting_with_name_are_substituted() {
+        let dir = write_files("name_prefix", &[
+            ("main.reson", "stock\n    template name_prefix\n    $name_prefix_box\n        resource\n        unit count\n        max 1\n\nline\n    instance stock\n    name_prefix big\n"),
+        ]);
+        let model = parse_simulation_file(dir.join("main.reson").to_str().unwrap()).unwrap();
+        assert!(model.resources.contains_key("line.big_box"), "{:?}", model.resources.keys());
+    }
+
+    #[test]
+    fn locations_and_transports() {
+        let model = parse_simulation_file("example/pencil_locations.reson").unwrap();
+        // One resource per location, the one defined on its own replacing the generated one
+        assert_eq!(model.resources["pencil@factory"].max, 100000.0);
+        assert_eq!(model.resources["pencil@warehouse"].location.as_deref(), Some("warehouse"));
+        assert_eq!(model.resources["pencil@store"].max, 5000.0);
+        assert_eq!(model.resources["pencil@store"].location.as_deref(), Some("store"));
+        assert!(!model.resources.contains_key("pencil"));
+
+        let transport = &model.processes["warehouse_to_store"];
+        assert_eq!(transport.input["pencil@warehouse"], 2000.0);
+        assert_eq!(transport.input["cad"], 20.0);
+        assert_eq!(transport.output["pencil@store"], 2000.0);
+        assert_eq!(transport.lead_time, 5 * 3600);
+        assert_eq!(transport.capacity, Some(3));
+
+        let error = parse_model("pencil\n    resource\n    locations factory\n\nmove\n    transport\n    from factory\n    to store\n    move\n        pencil 1\n    period 1 h\n").unwrap_err();
+        assert_eq!(error.to_string(), "Transport move moves pencil but there is no pencil@store");
+        let error = parse_model("move\n    transport\n    from factory\n    period 1 h\n").unwrap_err();
+        assert!(error.to_string().contains("needs `from` and `to`"), "{}", error);
+    }
+
+    #[test]
+    fn conversions_need_a_base_currency() {
+        let model = "cad\n    resource\n    unit cad\n\nusd\n    resource\n    unit usd\n\nbuy_usd\n    process\n    convert cad usd 100\n    period 1 d\n";
+        let dir = write_files("convert", &[
+            ("no_base.reson", model),
+            ("no_rate.reson", &format!("base_currency cad\n{}", model)),
+            ("rate.reson", &format!("base_currency cad\nusd\n    currency\n    rate 1.35\n{}", model)),
+        ]);
+        let error = parse_simulation_file(dir.join("no_base.reson").to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("needs a base_currency"), "{}", error);
+        let error = parse_simulation_file(dir.join("no_rate.reson").to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("no currency usd"), "{}", error);
+        let model = parse_simulation_file(dir.join("rate.reson").to_str().unwrap()).unwrap();
+        assert_eq!(model.exchange_rates["usd"].rate, 1.35);
+    }
+
+    #[test]
+    fn circular_includes_are_rejected() {
+        let dir = write_files("circular", &[
+            ("a.reson", "include b.reson\n"),
+            ("b.reson", "include a.reson\n"),
+        ]);
+        let error = parse_simulation_file(dir.join("a.reson").to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("Circular include"));
+    }
+
+    #[test]
+    fn circular_templates_are_rejected() {
+        let dir = write_files("circular_template", &[
+            ("self.reson", "t\n    template\n    inner\n        instance t\n\ny\n    instance t\n"),
+            ("mutual.reson", "a\n    template\n    inner\n        instance b\n\nb\n    template\n    inner\n        instance a\n\ny\n    instance a\n"),
+        ]);
+        for file in ["self.reson", "mutual.reson"] {
+            let error = parse_simulation_file(dir.join(file).to_str().unwrap()).unwrap_err();
+            assert!(error.to_string().contains("Circular template"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unknown_tokens_are_warnings_and_invalid_values_are_errors() {
+        let resource = "pencil\n    resource\n    unit count\n";
+        let error = |contents: &str| parse_model(contents).unwrap_err().to_string();
+        assert_eq!(error(&format!("{}    amount abc\n", resource)), "Invalid amount abc in pencil");
+        assert_eq!(error(&format!("{}    amount\n", resource)), "Missing value for amount in pencil");
+        assert!(error("make\n    process\n    period 0 s\n").contains("Invalid period 0"));
+        assert!(error("make\n    process\n    period 1 h\n    constraint\n        h 9-x\n").contains("Invalid constraint h 9-x"));
+        assert!(error("make\n    process\n    period 1 h\n    constraint\n        d 1\n").contains("Invalid constraint d 1"));
+        assert!(error("make\n    process\n    produce\n        pencil many\n    period 1 h\n").contains("Invalid entry pencil many"));
+
+        // A field without its value is an error rather than a panic
+        for (contents, field, name) in [
+            ("pencil\n    resource\n    unit\n", "unit", "pencil"),
+            ("restock\n    event\n    at\n", "at", "restock"),
+            ("move\n    transport\n    from\n", "from", "move"),
+            ("move\n    transport\n    to\n", "to", "move"),
+            ("penalty\n    process\n    overdraft\n", "overdraft", "penalty"),
+            ("make\n    process\n    start\n", "start", "make"),
+            ("make\n    process\n    end\n", "end", "make"),
+            ("credit\n    loan\n    account\n", "account", "credit"),
+            ("credit\n    loan\n    unit\n", "unit", "credit"),
+            ("credit\n    loan\n    start\n", "start", "credit"),
+            ("usd\n    currency\n    rate_file\n", "rate_file", "usd"),
+            ("stock\n    template size\n\nline\n    instance stock\n    size\n", "size", "line"),
+        ] {
+            assert_eq!(error(contents), format!("Missing value for {} in {}", field, name), "{}", contents);
+        }
+
+        // Unknown fields are skipped, so models with fields of other versions still load
+        let model = parse_model(&format!("{}    amout 5\n    amount 3\n\nmake\n    process\n    speed 2\n    period 1 h\n", resource)).unwrap();
+        assert_eq!(model.resources["pencil"].amount, 3.0);
+        assert_eq!(model.processes["make"].period, 3600);
+        assert_eq!(model.warnings, ["Unknown token amout in pencil, ignored", "Unknown token speed in make, ignored"]);
+        assert_eq!(model.problems(), model.warnings);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("12h"), Some(12 * 3600));
+        assert_eq!(parse_duration("6mo"), Some(6 * 2629800));
+        assert_eq!(parse_duration("1y2w3d"), Some(31557600 + 2 * 604800 + 3 * 86400));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("3x"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("999999999999999y"), None);
+        assert_eq!(parse_duration("584000000000y584000000000y"), None);
+    }
+}
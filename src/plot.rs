@@ -0,0 +1,372 @@
+use crate::resource::Model;
+use chrono::NaiveDateTime;
+use indexmap::IndexMap;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CHART_SIZE: (u32, u32) = (1024, 600);
+
+/// Image format the charts are written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "png" => Ok(ImageFormat::Png),
+            "svg" => Ok(ImageFormat::Svg),
+            _ => Err(format!("Unknown image format {}, expected png or svg", spec)),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+/// The columns of a simulation output as (timestamp, value) points, by column name.
+///
+/// Empty fields, like the fill rate of an interval without orders or a resource which doesn't
+/// exist yet, are skipped.
+pub struct Output {
+    pub columns: IndexMap<String, Vec<(i64, f64)>>,
+}
+
+impl Output {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let mut reader = csv::Reader::from_path(path)
+            .map_err(|e| io::Error::other(format!("{}: {}", path.display(), e)))?;
+        let headers = reader.headers()?.clone();
+        let mut columns: IndexMap<String, Vec<(i64, f64)>> = IndexMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let time = record.get(0).unwrap_or_default();
+            let Ok(time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S UTC") else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid time {} in {}", time, path.display())));
+            };
+            let timestamp = time.and_utc().timestamp();
+            for (header, field) in headers.iter().zip(record.iter()).skip(1) {
+                if let Ok(value) = field.parse::<f64>() {
+                    columns.entry(header.to_string()).or_default().push((timestamp, value));
+                }
+            }
+        }
+        Ok(Output { columns })
+    }
+
+    /// Returns the names of the resources, recognized by their `_min`, `_avg` and `_max` columns.
+    pub fn resources(&self) -> Vec<&str> {
+        self.columns.keys()
+            .filter(|name| ["_min", "_avg", "_max"].iter().all(|suffix| self.columns.contains_key(&format!("{}{}", name, suffix))))
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn first_timestamp(&self) -> Option<i64> {
+        self.columns.values().filter_map(|points| points.first()).map(|(time, _)| *time).min()
+    }
+
+    fn last_timestamp(&self) -> Option<i64> {
+        self.columns.values().filter_map(|points| points.last()).map(|(time, _)| *time).max()
+    }
+}
+
+/// A line of a chart, optionally with a band between the lowest and highest value of every write interval.
+pub struct Line {
+    pub name: String,
+    pub points: Vec<(f64, f64)>,
+    /// Points of (x, min, max).
+    pub band: Vec<(f64, f64, f64)>,
+    pub dashed: bool,
+}
+
+/// A chart of lines sharing one y axis.
+pub struct Chart {
+    /// Name of the file the chart is written to, without extension.
+    pub name: String,
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub lines: Vec<Line>,
+}
+
+impl Chart {
+    fn new(name: &str, title: &str, x_label: &str, y_label: &str) -> Self {
+        Chart {
+            name: name.to_string(),
+            title: title.to_string(),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let (mut x_min, mut x_max, mut y_min, mut y_max) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+        for line in &self.lines {
+            let points = line.points.iter().copied()
+                .chain(line.band.iter().flat_map(|(x, min, max)| [(*x, *min), (*x, *max)]));
+            for (x, y) in points {
+                x_min = x_min.min(x);
+                x_max = x_max.max(x);
+                y_min = y_min.min(y);
+                y_max = y_max.max(y);
+            }
+        }
+        if x_min > x_max {
+            return (0.0, 1.0, 0.0, 1.0);
+        }
+        if x_max == x_min {
+            x_max = x_min + 1.0;
+        }
+        let margin = if y_max > y_min { (y_max - y_min) * 0.05 } else { y_max.abs().max(1.0) * 0.1 };
+        (x_min, x_max, y_min - margin, y_max + margin)
+    }
+}
+
+/// The x axis of the charts of an output, in hours for short runs and days for longer ones.
+struct TimeAxis {
+    start: i64,
+    scale: f64,
+    label: String,
+}
+
+impl TimeAxis {
+    fn new(output: &Output) -> Option<Self> {
+        let (start, end) = (output.first_timestamp()?, output.last_timestamp()?);
+        let (scale, scale_name) = if end - start < 2 * 86400 { (3600.0, "hours") } else { (86400.0, "days") };
+        let label = format!("{} since {}", scale_name, chrono::DateTime::from_timestamp(start, 0)?);
+        Some(TimeAxis { start, scale, label })
+    }
+
+    /// Returns the points of a column with the time on this axis.
+    fn points(&self, output: &Output, column: &str) -> Vec<(f64, f64)> {
+        output.columns.get(column).map_or(Vec::new(), |points| {
+            points.iter().map(|(time, value)| ((time - self.start) as f64 / self.scale, *value)).collect()
+        })
+    }
+}
+
+/// Returns the average amount of a resource with its min/max band, and dashed what is on order.
+fn resource_lines(output: &Output, axis: &TimeAxis, resource_name: &str) -> Vec<Line> {
+    let min = axis.points(output, &format!("{}_min", resource_name));
+    let max = axis.points(output, &format!("{}_max", resource_name));
+    let mut lines = vec![Line {
+        name: resource_name.to_string(),
+        points: axis.points(output, &format!("{}_avg", resource_name)),
+        band: min.iter().zip(&max).map(|((x, min), (_, max))| (*x, *min, *max)).collect(),
+        dashed: false,
+    }];
+    let on_order = axis.points(output, &format!("{}_on_order", resource_name));
+    if !on_order.is_empty() {
+        lines.push(Line { name: format!("{} on order", resource_name), points: on_order, band: Vec::new(), dashed: true });
+    }
+    lines
+}
+
+/// Builds one chart per kind of process statistic: orders, fill rates, runs in transit,
+/// on-use processes and value.
+fn process_charts(output: &Output, model: &Model, axis: &TimeAxis) -> Vec<Chart> {
+    let kinds = [
+        ("_backlog", "orders", "Orders waiting and lost per interval", "orders"),
+        ("_lost", "orders", "Orders waiting and lost per interval", "orders"),
+        ("_fill_rate", "fill_rate", "Fill rate", "share of orders"),
+        ("_in_transit", "in_transit", "Runs in transit", "runs"),
+    ];
+    let mut charts: IndexMap<&str, Chart> = IndexMap::new();
+    for column in output.columns.keys() {
+        let kind = if model.on_use_processes.contains_key(column) {
+            Some(("", "on_use", "On-use processes", "used per interval"))
+        } else if column == "inventory_value" || column == "net_worth" || column.starts_with("value_") {
+            Some(("", "value", "Value", ""))
+        } else {
+            kinds.iter().copied().find(|(suffix, ..)| {
+                column.strip_suffix(suffix).is_some_and(|name| model.processes.contains_key(name))
+            })
+        };
+        let Some((_, name, title, y_label)) = kind else { continue };
+        let chart = charts.entry(name).or_insert_with(|| Chart::new(name, title, &axis.label, y_label));
+        chart.lines.push(Line { name: column.clone(), points: axis.points(output, column), band: Vec::new(), dashed: false });
+    }
+    charts.into_values().collect()
+}
+
+/// Builds the charts of an output: one per unit with the resources of that unit, their
+/// min/avg/max bands and what is on order, then one per kind of process statistic.
+///
+/// Only the resources in `selected` are drawn if it isn't empty. Units are taken from the model,
+/// resources it doesn't know are drawn together.
+pub fn build_charts(output: &Output, model: &Model, selected: &[String]) -> Vec<Chart> {
+    let Some(axis) = TimeAxis::new(output) else {
+        return Vec::new();
+    };
+    let mut charts: IndexMap<&str, Chart> = IndexMap::new();
+    for resource_name in output.resources() {
+        if !selected.is_empty() && !selected.iter().any(|name| name == resource_name) {
+            continue;
+        }
+        let unit = model.resources.get(resource_name).map_or("", |resource| resource.unit.as_str());
+        let chart = charts.entry(unit).or_insert_with(|| {
+            let title = if unit.is_empty() { "Other resources".to_string() } else { format!("Resources in {}", unit) };
+            Chart::new(&format!("resources_{}", if unit.is_empty() { "other" } else { unit }), &title, &axis.label, unit)
+        });
+        chart.lines.extend(resource_lines(output, &axis, resource_name));
+    }
+    charts.into_values().chain(process_charts(output, model, &axis)).collect()
+}
+
+/// Builds one chart per resource, followed by the charts of the process statistics.
+pub fn resource_charts(output: &Output, model: &Model) -> Vec<Chart> {
+    let Some(axis) = TimeAxis::new(output) else {
+        return Vec::new();
+    };
+    let mut charts: Vec<Chart> = output.resources().into_iter().map(|resource_name| {
+        let unit = model.resources.get(resource_name).map_or("", |resource| resource.unit.as_str());
+        let mut chart = Chart::new(resource_name, resource_name, &axis.label, unit);
+        chart.lines = resource_lines(output, &axis, resource_name);
+        chart
+    }).collect();
+    charts.extend(process_charts(output, model, &axis));
+    charts
+}
+
+fn plot_error<E: std::fmt::Display>(error: E) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+/// Draws a chart onto a drawing area of any backend.
+pub fn draw_chart<DB: DrawingBackend>(chart: &Chart, root: &DrawingArea<DB, Shift>) -> io::Result<()> {
+    root.fill(&WHITE).map_err(plot_error)?;
+    let (x_min, x_max, y_min, y_max) = chart.bounds();
+    let mut context = ChartBuilder::on(root)
+        .caption(&chart.title, ("sans-serif", 24))
+        .margin(12)
+        .x_label_area_size(40)
+        .y_label_area_size(80)
+        .build_cartesian_2d(x_min..x_max, y_min..y_max)
+        .map_err(plot_error)?;
+    context.configure_mesh().x_desc(&chart.x_label).y_desc(&chart.y_label).draw().map_err(plot_error)?;
+
+    for (i, line) in chart.lines.iter().enumerate() {
+        let color = Palette99::pick(i).to_rgba();
+        if !line.band.is_empty() {
+            let outline = line.band.iter().map(|(x, _, max)| (*x, *max))
+                .chain(line.band.iter().rev().map(|(x, min, _)| (*x, *min)))
+                .collect::<Vec<_>>();
+            context.draw_series(std::iter::once(Polygon::new(outline, color.mix(0.2)))).map_err(plot_error)?;
+        }
+        let style = color.stroke_width(2);
+        let series = if line.dashed {
+            context.draw_series(DashedLineSeries::new(line.points.clone(), 6, 4, style))
+        } else {
+            context.draw_series(LineSeries::new(line.points.clone(), style))
+        };
+        series.map_err(plot_error)?
+            .label(&line.name)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], style));
+    }
+    context.configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(plot_error)?;
+    root.present().map_err(plot_error)
+}
+
+/// Renders a chart as an SVG document.
+pub fn chart_svg(chart: &Chart) -> io::Result<String> {
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, CHART_SIZE).into_drawing_area();
+        draw_chart(chart, &root)?;
+    }
+    Ok(svg)
+}
+
+/// Writes every chart to `<name>.png` or `<name>.svg` in the directory, returning the paths.
+pub fn write_charts(charts: &[Chart], dir: &Path, format: ImageFormat) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut paths = Vec::new();
+    for chart in charts {
+        let path = dir.join(format!("{}.{}", chart.name, format.extension()));
+        match format {
+            ImageFormat::Png => draw_chart(chart, &BitMapBackend::new(&path, CHART_SIZE).into_drawing_area())?,
+            ImageFormat::Svg => draw_chart(chart, &SVGBackend::new(&path, CHART_SIZE).into_drawing_area())?,
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+
+    /// Output of two records an hour apart, with the values of every column.
+    fn output(columns: Vec<(String, [f64; 2])>) -> Output {
+        let start = 1_704_067_200;
+        Output {
+            columns: columns.into_iter()
+                .map(|(name, values)| (name, vec![(start, values[0]), (start + 3600, values[1])]))
+                .collect(),
+        }
+    }
+
+    fn model() -> Model {
+        parse_model("cad\n    resource\n    unit cad\n\npencil\n    resource\n    unit count\n\ntruck\n    resource\n    unit count\n\nsell_pencil\n    demand\n    orders 1\n    use\n        pencil 1\n    period 1 h\n").unwrap()
+    }
+
+    #[test]
+    fn charts_by_unit_and_process_statistic() {
+        let mut columns: Vec<(String, [f64; 2])> = ["cad", "pencil", "truck"].into_iter()
+            .flat_map(|name| ["", "_min", "_avg", "_max"].map(|suffix| (format!("{}{}", name, suffix), [1.0, 2.0])))
+            .collect();
+        for (name, values) in [
+            ("pencil_on_order", [0.0, 5.0]),
+            ("sell_pencil_backlog", [1.0, 0.0]),
+            ("sell_pencil_fill_rate", [1.0, 1.0]),
+            ("net_worth", [10.0, 12.0]),
+        ] {
+            columns.push((name.to_string(), values));
+        }
+        let output = output(columns);
+        assert_eq!(output.resources(), ["cad", "pencil", "truck"]);
+
+        let charts = build_charts(&output, &model(), &[]);
+        let summary: Vec<(&str, Vec<&str>)> = charts.iter()
+            .map(|chart| (chart.name.as_str(), chart.lines.iter().map(|line| line.name.as_str()).collect()))
+            .collect();
+        assert_eq!(summary, [
+            ("resources_cad", vec!["cad"]),
+            ("resources_count", vec!["pencil", "pencil on order", "truck"]),
+            ("orders", vec!["sell_pencil_backlog"]),
+            ("fill_rate", vec!["sell_pencil_fill_rate"]),
+            ("value", vec!["net_worth"]),
+        ]);
+        let pencil = &charts[1].lines[0];
+        assert_eq!(pencil.points, [(0.0, 1.0), (1.0, 2.0)]);
+        assert_eq!(pencil.band, [(0.0, 1.0, 1.0), (1.0, 2.0, 2.0)]);
+        assert!(charts[1].lines[1].dashed);
+        assert_eq!(charts[0].x_label, "hours since 2024-01-01 00:00:00 UTC");
+
+        let selected = build_charts(&output, &model(), &["truck".to_string()]);
+        assert_eq!(selected[0].lines.len(), 1);
+        assert_eq!(selected[0].lines[0].name, "truck");
+        assert!(chart_svg(&charts[0]).unwrap().starts_with("<svg"));
+    }
+
+    #[test]
+    fn no_charts_without_records() {
+        assert!(build_charts(&Output { columns: IndexMap::new() }, &model(), &[]).is_empty());
+    }
+}
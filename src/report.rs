@@ -0,0 +1,219 @@
+use crate::inspect::{describe, format_duration};
+use crate::plot::{chart_svg, resource_charts, Output};
+use crate::resource::Model;
+use crate::simulation::Simulation;
+use crate::throughput::Throughput;
+use chrono::DateTime;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Processes making fewer of their possible runs than this are reported as held back.
+const LOW_UTILIZATION: f64 = 0.9;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 1100px; color: #222; }
+h1, h2 { font-weight: normal; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+figure { margin: 1em 0; }
+svg { max-width: 100%; height: auto; }
+pre { background: #f6f6f6; padding: 1em; overflow-x: auto; }
+";
+
+/// Escapes text for HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn format_time(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0).map_or(timestamp.to_string(), |time| time.to_string())
+}
+
+/// Formats an amount with at most two decimals.
+fn format_amount(amount: f64) -> String {
+    let text = format!("{:.2}", amount);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn format_percent(share: f64) -> String {
+    format!("{:.1} %", share * 100.0)
+}
+
+/// Finds what held the processes back and which resources ran out, in plain sentences.
+fn bottlenecks(sim: &Simulation, model: &Model, output: &Output) -> Vec<String> {
+    let mut findings = Vec::new();
+    for (name, process) in sim.processes.iter().chain(&sim.on_use_processes) {
+        let Some(utilization) = process.stats.utilization() else { continue };
+        if utilization >= LOW_UTILIZATION {
+            continue;
+        }
+        let mut finding = format!("{} made {} of its possible runs", name, format_percent(utilization));
+        if let Some((cause, count)) = process.stats.held_back_by.iter().max_by_key(|(_, count)| **count) {
+            let cause = if cause == "capacity" {
+                "its capacity".to_string()
+            } else if process.output.contains_key(cause) {
+                format!("{} being full", cause)
+            } else {
+                format!("a lack of {}", cause)
+            };
+            let share = *count as f64 / process.stats.due as f64;
+            write!(finding, ", held back by {} {} of the times it was due", cause, format_percent(share)).unwrap();
+        }
+        findings.push(finding + ".");
+    }
+
+    let seconds = sim.time.timestamp() as u64 - sim.start_time;
+    for (name, resource) in &sim.resources {
+        let used = sim.processes.values().chain(sim.on_use_processes.values()).any(|process| process.input.contains_key(name));
+        if used && resource.stockout_seconds > 0 && seconds > 0 {
+            findings.push(format!(
+                "{} was out of stock for {} ({} of the run).",
+                name,
+                format_duration(resource.stockout_seconds),
+                format_percent(resource.stockout_seconds as f64 / seconds as f64)
+            ));
+        }
+    }
+
+    for (name, process) in &sim.processes {
+        if process.demand.is_none() {
+            continue;
+        }
+        let lost = output.columns.get(&format!("{}_lost", name)).map_or(0.0, |points| points.iter().fold(0.0, |total, (_, lost)| total + lost));
+        if lost > 0.0 {
+            findings.push(format!("{} lost {} orders.", name, lost));
+        }
+    }
+
+    for (name, flow) in Throughput::analyze(model).negative_balances() {
+        findings.push(format!(
+            "Even with every process at full rate, {} is used faster than it is produced ({:.2} {} per day).",
+            name,
+            flow.net_per_day(),
+            flow.unit
+        ));
+    }
+    findings
+}
+
+/// Builds a self-contained HTML report of a finished run: final state, process utilization,
+/// bottlenecks, a chart of every resource and the model as it was at the start.
+pub fn build_report(title: &str, sim: &Simulation, model: &Model, output: &Output) -> io::Result<String> {
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">").unwrap();
+    writeln!(html, "<title>{}</title>\n<style>{}</style>\n</head>\n<body>", escape(title), STYLE).unwrap();
+    writeln!(html, "<h1>{}</h1>", escape(title)).unwrap();
+    let end = sim.time.timestamp() as u64;
+    writeln!(
+        html,
+        "<p>Simulated from {} to {} ({}).</p>",
+        format_time(sim.start_time),
+        format_time(end),
+        format_duration(end.saturating_sub(sim.start_time))
+    ).unwrap();
+
+    writeln!(html, "<h2>Bottlenecks</h2>").unwrap();
+    let findings = bottlenecks(sim, model, output);
+    if findings.is_empty() {
+        writeln!(html, "<p>No bottlenecks found, every process made at least {} of its possible runs.</p>", format_percent(LOW_UTILIZATION)).unwrap();
+    } else {
+        writeln!(html, "<ul>").unwrap();
+        for finding in findings {
+            writeln!(html, "<li>{}</li>", escape(&finding)).unwrap();
+        }
+        writeln!(html, "</ul>").unwrap();
+    }
+
+    writeln!(html, "<h2>Final state</h2>").unwrap();
+    let valued = sim.has_valuation();
+    writeln!(html, "<table>\n<tr><th>Resource</th><th>Amount</th><th>Unit</th><th>Lowest</th><th>Out of stock</th>{}</tr>", if valued { "<th>Value</th>" } else { "" }).unwrap();
+    for (name, resource) in &sim.resources {
+        let lowest = if resource.resource_min_for_run == f64::MAX { resource.amount } else { resource.resource_min_for_run };
+        write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+            escape(name),
+            format_amount(resource.amount),
+            escape(&resource.unit),
+            format_amount(lowest),
+            if resource.stockout_seconds > 0 { format_duration(resource.stockout_seconds) } else { "-".to_string() }
+        ).unwrap();
+        if valued {
+            write!(html, "<td>{:.2}</td>", sim.resource_value(name)).unwrap();
+        }
+        writeln!(html, "</tr>").unwrap();
+    }
+    writeln!(html, "</table>").unwrap();
+    if valued {
+        writeln!(html, "<p>Inventory value {:.2}, net worth {:.2}.</p>", sim.inventory_value(), sim.net_worth()).unwrap();
+    }
+    if let Some(base_currency) = &sim.base_currency {
+        writeln!(html, "<p>Consolidated value {:.2} {}.</p>", sim.consolidated_value(), escape(base_currency)).unwrap();
+    }
+
+    writeln!(html, "<h2>Process utilization</h2>").unwrap();
+    writeln!(html, "<table>\n<tr><th>Process</th><th>Due</th><th>Runs</th><th>Possible runs</th><th>Utilization</th><th>Held back most by</th></tr>").unwrap();
+    for (name, process) in sim.processes.iter().chain(&sim.on_use_processes) {
+        let stats = &process.stats;
+        let held_back = stats.held_back_by.iter().max_by_key(|(_, count)| **count)
+            .map_or("-".to_string(), |(cause, count)| format!("{} ({} times)", cause, count));
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(name),
+            stats.due,
+            format_amount(stats.runs),
+            format_amount(stats.possible_runs),
+            stats.utilization().map_or("-".to_string(), format_percent),
+            escape(&held_back)
+        ).unwrap();
+    }
+    writeln!(html, "</table>").unwrap();
+    writeln!(html, "<p>Demand and overdraft processes run on orders and debts rather than their period and aren't counted here.</p>").unwrap();
+
+    writeln!(html, "<h2>Charts</h2>").unwrap();
+    for chart in resource_charts(output, &sim.model()) {
+        writeln!(html, "<figure>\n{}\n</figure>", chart_svg(&chart)?).unwrap();
+    }
+
+    writeln!(html, "<h2>Model</h2>\n<pre>{}</pre>", escape(&describe(model))).unwrap();
+    writeln!(html, "</body>\n</html>").unwrap();
+    Ok(html)
+}
+
+/// Writes the report of a finished run to a file.
+pub fn write_report(path: &Path, title: &str, sim: &Simulation, model: &Model, output: &Output) -> io::Result<()> {
+    fs::write(path, build_report(title, sim, model, output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+    use chrono::{TimeZone, Utc};
+    use indexmap::IndexMap;
+
+    #[test]
+    fn report_of_a_run_held_back_by_a_resource() {
+        let model = parse_model("wood\n    resource\n    amount 3\n\npencil\n    resource\n\nmake_pencil\n    process\n    use\n        wood 1\n    produce\n        pencil 1\n    period 1 s\n").unwrap();
+        let mut sim = Simulation::new(model.clone(), Box::new(io::sink()));
+        sim.set_start_time(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        sim.run(10);
+
+        let output = Output { columns: IndexMap::new() };
+        assert_eq!(bottlenecks(&sim, &model, &output), [
+            "make_pencil made 30.0 % of its possible runs, held back by a lack of wood 70.0 % of the times it was due.",
+            "wood was out of stock for 8s (80.0 % of the run).",
+            "Even with every process at full rate, wood is used faster than it is produced (-86400.00  per day).",
+        ]);
+
+        let html = build_report("Pencils <draft>", &sim, &model, &output).unwrap();
+        assert!(html.contains("<h1>Pencils &lt;draft&gt;</h1>"));
+        assert!(html.contains("<p>Simulated from 2024-01-01 00:00:00 UTC to 2024-01-01 00:00:10 UTC (10s).</p>"));
+        assert!(html.contains("<tr><td>wood</td><td>0</td><td></td><td>0</td><td>8s</td></tr>"));
+        assert!(html.contains("<tr><td>make_pencil</td><td>10</td><td>3</td><td>10</td><td>30.0 %</td><td>wood (7 times)</td></tr>"));
+    }
+}
@@ -1,6 +1,12 @@
+use crate::expr::Expr;
 use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+/// Variables a value expression can use besides resource amounts and currency rates:
+/// the amount of the valued resource itself and the days since the start of the run.
+pub const VALUE_VARIABLES: [&str; 2] = ["amount", "days"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Resource {
     pub unit: String,
     pub max: f64,
@@ -12,6 +18,16 @@ pub struct Resource {
     pub resource_min_for_writer: f64,
     pub resource_max_for_writer: f64,
     pub resource_avg_for_writer: f64,
+    pub resource_min_for_run: f64,
+    pub stockout_seconds: u64,
+    #[serde(default)]
+    pub value: Option<Expr>,
+    /// Lowest amount processes may take the resource down to, negative for a credit line.
+    #[serde(default)]
+    pub min: f64,
+    /// Location the resource is stored at, for resources named like `pencil@store`.
+    #[serde(default)]
+    pub location: Option<String>,
 }
 
 impl Default for Resource {
@@ -25,13 +41,47 @@ impl Default for Resource {
             decay_amount: Vec::new(),
             amount_used_as_catalyst: 0.0,
             resource_min_for_writer: f64::MAX,
-            resource_max_for_writer: 0.0,
+            resource_max_for_writer: f64::MIN,
             resource_avg_for_writer: 0.0,
+            resource_min_for_run: f64::MAX,
+            stockout_seconds: 0,
+            value: None,
+            min: 0.0,
+            location: None,
+        }
+    }
+}
+
+impl Resource {
+    /// Adds an amount, scheduling its decay if the resource has a life.
+    pub fn add(&mut self, amount: f64, now: u64) {
+        self.amount += amount;
+        if self.life > 0 {
+            self.decay_at.push(now + self.life);
+            self.decay_amount.push(amount);
+        }
+    }
+
+    /// Removes an amount, taking it out of the pending decays as well.
+    pub fn remove(&mut self, amount: f64) {
+        self.amount -= amount;
+        // Deduct the decayed amount from the latest decay if exists
+        if !self.decay_at.is_empty() {
+            let mut amount_to_deduct = amount;
+            for i in 0..self.decay_at.len() {
+                self.decay_amount[i] -= amount_to_deduct;
+                if self.decay_amount[i] < 0.0 {
+                    amount_to_deduct = -self.decay_amount[i];
+                    self.decay_amount[i] = 0.0;
+                } else {
+                    break;
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Process {
     pub input: IndexMap<String, f64>,
     pub output: IndexMap<String, f64>,
@@ -44,6 +94,44 @@ pub struct Process {
     pub on_use: f64,
     pub on_use_accumulate: f64,
     pub on_use_accumulate_for_writer: f64,
+    /// Units the quantities were written in, keyed like `use.wood`. The parser converts
+    /// the quantities into the unit of each resource, so they're kept for reference only.
+    #[serde(default)]
+    pub units: IndexMap<String, String>,
+    /// Converts its `use` currency into its `produce` currency at the current exchange rates.
+    #[serde(default)]
+    pub convert: bool,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// The process only runs from `start` (inclusive) to `end` (exclusive), as timestamps.
+    #[serde(default)]
+    pub start: Option<u64>,
+    #[serde(default)]
+    pub end: Option<u64>,
+    /// Runs only while this resource is below zero, with its quantities multiplied by the overdrawn amount.
+    #[serde(default)]
+    pub overdraft: Option<String>,
+    /// Makes the process a demand, whose orders wait in a backlog until they can be served.
+    #[serde(default)]
+    pub demand: Option<Demand>,
+    /// Seconds until the outputs arrive after the process ran.
+    #[serde(default)]
+    pub lead_time: u64,
+    /// Maximum number of runs whose outputs are still on the way.
+    #[serde(default)]
+    pub capacity: Option<u64>,
+    /// Runs whose outputs are on the way as (arrival time, runs), in order of arrival.
+    #[serde(default)]
+    pub in_transit: Vec<(u64, f64)>,
+    /// Makes the process a transport, moving goods between locations.
+    #[serde(default)]
+    pub transport: Option<Transport>,
+    #[serde(default)]
+    pub stats: ProcessStats,
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 impl Default for Process {
@@ -60,6 +148,366 @@ impl Default for Process {
             on_use: 0.0,
             on_use_accumulate: 0.0,
             on_use_accumulate_for_writer: 0.0,
+            units: IndexMap::new(),
+            convert: false,
+            enabled: true,
+            start: None,
+            end: None,
+            overdraft: None,
+            demand: None,
+            lead_time: 0,
+            capacity: None,
+            in_transit: Vec::new(),
+            transport: None,
+            stats: ProcessStats::default(),
+        }
+    }
+}
+
+impl Process {
+    /// Whether the process is enabled and within its start/end window.
+    pub fn is_active(&self, now: u64) -> bool {
+        self.enabled
+            && self.start.is_none_or(|start| now >= start)
+            && self.end.is_none_or(|end| now < end)
+    }
+
+    /// Checks if the time satisfies the constraints of the process.
+    pub fn constraints_allow(&self, now: u64) -> bool {
+        for (i, constraint_list) in self.constraint.iter().enumerate() {
+            let modulo = self.constraint_modulo[i];
+            let mut feasible_for_this_modulo = false;
+            let time_to_check = now % modulo;
+            for [start, end] in constraint_list {
+                if (*start <= *end && time_to_check >= *start && time_to_check <= *end) ||
+                   (*start > *end && (time_to_check >= *start || time_to_check <= *end)) {
+                    feasible_for_this_modulo = true;
+                    break;
+                }
+            }
+            if !feasible_for_this_modulo {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the number of runs whose outputs haven't arrived yet.
+    pub fn runs_in_transit(&self) -> f64 {
+        self.in_transit.iter().fold(0.0, |total, (_, runs)| total + runs)
+    }
+}
+
+/// How much a process ran over the whole simulation and what held it back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProcessStats {
+    /// Times the process was due, every second for on-use processes.
+    pub due: u64,
+    /// Runs made, for on-use processes the amount used.
+    pub runs: f64,
+    /// Most runs the process could have made, its `max_catalyst` (or `on_use`) every time it was due.
+    pub possible_runs: f64,
+    /// Times the process made fewer runs than it could, by the resource which held it back.
+    pub held_back_by: IndexMap<String, u64>,
+}
+
+impl ProcessStats {
+    /// Records a time the process was due.
+    pub fn record(&mut self, runs: f64, possible_runs: f64, held_back_by: Option<&str>) {
+        self.due += 1;
+        self.runs += runs;
+        self.possible_runs += possible_runs;
+        if let (true, Some(name)) = (runs < possible_runs, held_back_by) {
+            match self.held_back_by.get_mut(name) {
+                Some(count) => *count += 1,
+                None => {
+                    self.held_back_by.insert(name.to_string(), 1);
+                }
+            }
+        }
+    }
+
+    /// Share of the possible runs the process made.
+    pub fn utilization(&self) -> Option<f64> {
+        (self.possible_runs > 0.0).then(|| self.runs / self.possible_runs)
+    }
+}
+
+/// Goods a transport process moves on every run, from `goods@from` to `goods@to`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Transport {
+    pub from: String,
+    pub to: String,
+    pub goods: IndexMap<String, f64>,
+}
+
+/// Orders of a demand process and what happened to them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Demand {
+    /// Orders placed every time the process is due.
+    pub orders: u64,
+    /// Orders which weren't served within this time are lost, they wait forever if not set.
+    pub expire: Option<u64>,
+    /// Resources taken for every lost order.
+    pub penalty: IndexMap<String, f64>,
+    /// Orders waiting to be served as (time ordered, orders), oldest first.
+    pub backlog: Vec<(u64, u64)>,
+    pub ordered_for_writer: u64,
+    pub filled_for_writer: u64,
+    pub lost_for_writer: u64,
+}
+
+impl Default for Demand {
+    fn default() -> Self {
+        Demand {
+            orders: 1,
+            expire: None,
+            penalty: IndexMap::new(),
+            backlog: Vec::new(),
+            ordered_for_writer: 0,
+            filled_for_writer: 0,
+            lost_for_writer: 0,
+        }
+    }
+}
+
+impl Demand {
+    /// Returns the number of orders waiting to be served.
+    pub fn waiting(&self) -> u64 {
+        self.backlog.iter().map(|(_, orders)| orders).sum()
+    }
+
+    /// Serves orders, oldest first, counting the ones served the moment they were ordered as filled.
+    pub fn serve(&mut self, mut served: u64, now: u64) {
+        while served > 0 && !self.backlog.is_empty() {
+            let (time, orders) = &mut self.backlog[0];
+            let taken = served.min(*orders);
+            if *time == now {
+                self.filled_for_writer += taken;
+            }
+            *orders -= taken;
+            served -= taken;
+            if *orders == 0 {
+                self.backlog.remove(0);
+            }
+        }
+    }
+
+    /// Drops the orders which waited for too long, returning how many were lost.
+    pub fn expire(&mut self, now: u64) -> u64 {
+        let Some(expire) = self.expire else { return 0 };
+        let expired = self.backlog.partition_point(|(time, _)| now - time >= expire);
+        let lost = self.backlog.drain(..expired).map(|(_, orders)| orders).sum();
+        self.lost_for_writer += lost;
+        lost
+    }
+}
+
+/// A change applied to a running simulation, by a scheduled event or when forking a what-if branch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Modification {
+    /// Adds (or with a negative amount removes) an amount of a resource.
+    AddAmount { resource: String, amount: f64 },
+    /// Sets a parameter, see `parameter::set_parameter` for the paths.
+    SetParameter { path: String, value: f64 },
+    /// Multiplies a parameter by a factor.
+    ScaleParameter { path: String, factor: f64 },
+    /// Adds a process, or replaces the process with the same name.
+    AddProcess { name: String, process: Box<Process> },
+    /// Removes a process.
+    RemoveProcess { name: String },
+    /// Adds a resource, or replaces the resource with the same name.
+    AddResource { name: String, resource: Box<Resource> },
+    /// Removes a resource.
+    RemoveResource { name: String },
+    /// Lets a disabled process run again.
+    EnableProcess { name: String },
+    /// Stops a process from running without removing it.
+    DisableProcess { name: String },
+}
+
+/// When an event happens, as a timestamp or an offset from the start of the simulation.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum EventTime {
+    At(u64),
+    After(u64),
+}
+
+/// Modifications applied once the simulation reaches the time of the event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: EventTime,
+    pub modifications: Vec<Modification>,
+}
+
+/// The value of one unit of a currency in the base currency, constant or following a time series.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub rate: f64,
+    /// Points of (timestamp, rate) sorted by time, the rate steps to each point once it is reached.
+    pub series: Vec<(u64, f64)>,
+}
+
+impl Default for ExchangeRate {
+    fn default() -> Self {
+        ExchangeRate {
+            rate: 1.0,
+            series: Vec::new(),
         }
     }
 }
+
+impl ExchangeRate {
+    /// Moves the rate to the latest point of the series at or before now, returning whether it changed.
+    pub fn update(&mut self, now: u64) -> bool {
+        let reached = self.series.partition_point(|(time, _)| *time <= now);
+        let previous = self.rate;
+        if reached > 0 {
+            self.rate = self.series[reached - 1].1;
+        }
+        self.rate != previous
+    }
+}
+
+/// Seconds in a year, which interest rates are given for.
+pub const SECONDS_PER_YEAR: u64 = 31557600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InstrumentKind {
+    /// Pays out the principal into the account, then takes amortized payments from it every period.
+    Loan,
+    /// Adds interest to its balance every period.
+    Savings,
+}
+
+/// A loan or savings account settled every period.
+///
+/// Its balance is a resource with the name of the instrument, negative (a debt) for loans.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Instrument {
+    pub kind: InstrumentKind,
+    /// Money resource a loan is paid into and paid back from.
+    pub account: Option<String>,
+    pub principal: f64,
+    /// Annual interest rate, 0.05 for 5 %.
+    pub interest: f64,
+    pub term: u64,
+    pub period: u64,
+    pub period_delta: u64,
+    /// Time the loan is paid out or the savings account opened, the start of the simulation if not set.
+    pub start: Option<u64>,
+    /// Amount paid every period, computed once the loan is paid out.
+    pub payment: f64,
+    pub payments_left: u64,
+    /// Time the instrument was actually opened, interest and payments are due from the next period on.
+    pub opened_at: Option<u64>,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument {
+            kind: InstrumentKind::Loan,
+            account: None,
+            principal: 0.0,
+            interest: 0.0,
+            term: 0,
+            period: 30 * 86400,
+            period_delta: 0,
+            start: None,
+            payment: 0.0,
+            payments_left: 0,
+            opened_at: None,
+        }
+    }
+}
+
+impl Instrument {
+    /// Interest rate for one period.
+    pub fn period_rate(&self) -> f64 {
+        self.interest * self.period as f64 / SECONDS_PER_YEAR as f64
+    }
+
+    /// Computes the annuity payment which pays the principal back with interest over the term.
+    pub fn amortize(&mut self) {
+        self.payments_left = (self.term / self.period).max(1);
+        let periods = self.payments_left as f64;
+        let rate = self.period_rate();
+        self.payment = if rate == 0.0 {
+            self.principal / periods
+        } else {
+            self.principal * rate / (1.0 - (1.0 + rate).powf(-periods))
+        };
+    }
+}
+
+/// Everything defined by a .reson file.
+#[derive(Debug, Clone, Default)]
+pub struct Model {
+    pub resources: IndexMap<String, Resource>,
+    pub processes: IndexMap<String, Process>,
+    pub on_use_processes: IndexMap<String, Process>,
+    pub events: IndexMap<String, Event>,
+    /// Currency code the value of all currency resources is consolidated in.
+    pub base_currency: Option<String>,
+    /// Exchange rates into the base currency, by currency code.
+    pub exchange_rates: IndexMap<String, ExchangeRate>,
+    /// Loans and savings accounts, by the name of their balance resource.
+    pub instruments: IndexMap<String, Instrument>,
+    /// Lines the parser ignored, like unknown fields.
+    pub warnings: Vec<String>,
+}
+
+impl Model {
+    /// Returns the problems which would keep the model from simulating as intended,
+    /// like processes using resources which don't exist or lines the parser ignored.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.warnings.clone();
+        for (name, process) in self.processes.iter().chain(&self.on_use_processes) {
+            if process.period == 0 && process.on_use == 0.0 {
+                problems.push(format!("Process {} has no period", name));
+            }
+            for resource_name in process.input.keys() {
+                if !self.resources.contains_key(resource_name) && !self.on_use_processes.contains_key(resource_name) {
+                    problems.push(format!("Process {} uses unknown resource {}", name, resource_name));
+                }
+            }
+            for resource_name in process.output.keys().chain(process.catalyst.keys()) {
+                if !self.resources.contains_key(resource_name) {
+                    problems.push(format!("Process {} produces or is catalyzed by unknown resource {}", name, resource_name));
+                }
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loan_amortization() {
+        let mut loan = Instrument {
+            principal: 10000.0,
+            interest: 0.06,
+            term: SECONDS_PER_YEAR,
+            period: SECONDS_PER_YEAR / 12,
+            ..Instrument::default()
+        };
+        loan.amortize();
+        assert_eq!(loan.payments_left, 12);
+        assert!((loan.period_rate() - 0.005).abs() < 1e-12);
+        // Standard annuity of 10000 at 0.5 % a month over 12 months
+        assert!((loan.payment - 860.664).abs() < 1e-3, "{}", loan.payment);
+
+        loan.interest = 0.0;
+        loan.amortize();
+        assert!((loan.payment - 10000.0 / 12.0).abs() < 1e-9);
+
+        // A term shorter than a period is paid back at once
+        loan.term = 86400;
+        loan.amortize();
+        assert_eq!(loan.payments_left, 1);
+        assert_eq!(loan.payment, 10000.0);
+    }
+}
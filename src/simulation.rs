@@ -1,72 +1,413 @@
-use std::time;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use indexmap::IndexMap;
-use crate::resource::{Process, Resource};
+use crate::ledger::Ledger;
+use crate::observer::{Observer, Observers};
+use crate::parameter::{get_parameter, set_parameter};
+use crate::units;
+use crate::resource::{Event, EventTime, ExchangeRate, Instrument, InstrumentKind, Model, Modification, Process, Resource};
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
 
 /// Represents a simulation with resources, processes, and time tracking.
+///
+/// Everything except the CSV output and the observers is serializable, so a checkpoint holds the complete
+/// state including decay queues and on-use accumulators.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Simulation {
     pub resources: IndexMap<String, Resource>,
     pub processes: IndexMap<String, Process>,
     pub on_use_processes: IndexMap<String, Process>,
+    #[serde(default)]
+    pub events: IndexMap<String, Event>,
+    #[serde(default)]
+    pub base_currency: Option<String>,
+    #[serde(default)]
+    pub exchange_rates: IndexMap<String, ExchangeRate>,
+    #[serde(default)]
+    pub instruments: IndexMap<String, Instrument>,
+    #[serde(default)]
+    pub ledger: Ledger,
     pub time: DateTime<Utc>,
-    pub csv_writer: csv::Writer<std::fs::File>,
+    #[serde(default)]
+    pub start_time: u64,
+    #[serde(skip)]
+    pub csv_writer: CsvOutput,
+    /// The columns of the CSV output, fixed when a writer is attached so every row has the same ones.
+    #[serde(default)]
+    columns: Vec<String>,
+    #[serde(skip)]
+    observers: Observers,
+    /// Monetary resources with their rates for the ledger, dropped whenever resources or rates change.
+    #[serde(skip)]
+    ledger_rates: Option<Arc<IndexMap<String, Option<f64>>>>,
+    /// Which on-use processes could be fed in the current tick.
+    #[serde(skip)]
+    on_use_fed: Vec<bool>,
+    /// Amounts on order per resource, counted before processes run and kept up to date while they run.
+    #[serde(skip)]
+    on_order_totals: IndexMap<String, f64>,
     pub write_every: u64,
     pub last_write_time: u64,
 }
 
+/// The CSV output of a simulation, which is not part of its state.
+/// Records are dropped while no writer is attached.
+#[derive(Default)]
+pub struct CsvOutput(Option<csv::Writer<Box<dyn Write>>>);
+
+/// Clones of a simulation start without an output, as two branches can't share one file.
+impl Clone for CsvOutput {
+    fn clone(&self) -> Self {
+        CsvOutput::default()
+    }
+}
+
+impl CsvOutput {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        CsvOutput(Some(csv::Writer::from_writer(writer)))
+    }
+
+    pub fn write_record(&mut self, record: &[String]) -> csv::Result<()> {
+        match &mut self.0 {
+            Some(writer) => writer.write_record(record),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Stops a run from another thread or a signal handler. Clones share the same flag.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a run has got, passed to the progress callback of `run_with`.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Seconds simulated so far in this run.
+    pub simulated: u64,
+    pub duration: u64,
+    pub time: DateTime<Utc>,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.duration == 0 { 1.0 } else { self.simulated as f64 / self.duration as f64 }
+    }
+}
+
 impl Simulation {
-    /// Creates a new simulation instance.
-    pub fn new(resources: IndexMap<String, Resource>, processes: IndexMap<String, Process>, on_use_processes: IndexMap<String, Process>) -> Self {
+    /// Creates a new simulation instance of a model writing its output to the given writer.
+    pub fn new(model: Model, writer: Box<dyn Write>) -> Self {
+        let time = Utc::now();
         let mut sim = Simulation {
-            resources,
-            processes,
-            on_use_processes,
-            time: Utc::now(),
-            csv_writer: csv::Writer::from_path("output.csv").unwrap(),
+            resources: model.resources,
+            processes: model.processes,
+            on_use_processes: model.on_use_processes,
+            events: model.events,
+            base_currency: model.base_currency,
+            exchange_rates: model.exchange_rates,
+            instruments: model.instruments,
+            ledger: Ledger::default(),
+            time,
+            start_time: time.timestamp() as u64,
+            csv_writer: CsvOutput::new(writer),
+            columns: Vec::new(),
+            observers: Observers::default(),
+            ledger_rates: None,
+            on_use_fed: Vec::new(),
+            on_order_totals: IndexMap::new(),
             write_every: 1,
             last_write_time: 0,
         };
+        sim.columns = sim.output_columns();
+        sim.write_headers();
+
+        sim
+    }
+
+    /// Loads a simulation from a checkpoint, without an output attached.
+    pub fn load_checkpoint(path: &Path) -> io::Result<Self> {
+        let file = io::BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Saves the complete state of the simulation to a checkpoint.
+    pub fn save_checkpoint(&mut self, path: &Path) -> io::Result<()> {
+        self.csv_writer.flush()?;
+        let mut file = io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()
+    }
+
+    /// Clones the simulation state into a new branch without an output attached.
+    /// Apply modifications and attach a writer with `set_writer` before running it.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Returns the current resources, processes, events and instruments as a model.
+    pub fn model(&self) -> Model {
+        Model {
+            resources: self.resources.clone(),
+            processes: self.processes.clone(),
+            on_use_processes: self.on_use_processes.clone(),
+            events: self.events.clone(),
+            base_currency: self.base_currency.clone(),
+            exchange_rates: self.exchange_rates.clone(),
+            instruments: self.instruments.clone(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Applies modifications to the state, stopping at the first one which fails.
+    pub fn apply_modifications(&mut self, modifications: &[Modification]) -> Result<(), String> {
+        let now = self.time.timestamp() as u64;
+        for modification in modifications {
+            match modification {
+                Modification::AddAmount { resource, amount } => {
+                    let resource = self.resources.get_mut(resource)
+                        .ok_or_else(|| format!("Unknown resource {}", resource))?;
+                    if *amount >= 0.0 {
+                        resource.add(*amount, now);
+                    } else {
+                        resource.remove(-amount);
+                    }
+                }
+                Modification::SetParameter { path, value } => {
+                    set_parameter(&mut self.resources, &mut self.processes, &mut self.on_use_processes, path, *value)?;
+                }
+                Modification::ScaleParameter { path, factor } => {
+                    let value = get_parameter(&self.resources, &self.processes, &self.on_use_processes, path)?;
+                    set_parameter(&mut self.resources, &mut self.processes, &mut self.on_use_processes, path, value * factor)?;
+                }
+                Modification::AddProcess { name, process } => {
+                    self.add_process(name, *process.clone());
+                }
+                Modification::RemoveProcess { name } => {
+                    self.remove_process(name).ok_or_else(|| format!("Unknown process {}", name))?;
+                }
+                Modification::AddResource { name, resource } => {
+                    self.add_resource(name, *resource.clone());
+                }
+                Modification::RemoveResource { name } => {
+                    self.remove_resource(name).ok_or_else(|| format!("Unknown resource {}", name))?;
+                }
+                Modification::EnableProcess { name } | Modification::DisableProcess { name } => {
+                    let process = self.process_mut(name).ok_or_else(|| format!("Unknown process {}", name))?;
+                    process.enabled = matches!(modification, Modification::EnableProcess { .. });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Attaches the writer the output is written to, optionally starting with the headers.
+    /// Without them the writer is assumed to continue a file which already has them, and the
+    /// columns of that file are kept.
+    pub fn set_writer(&mut self, writer: Box<dyn Write>, write_headers: bool) {
+        self.csv_writer = CsvOutput::new(writer);
+        if write_headers || self.columns.is_empty() {
+            self.columns = self.output_columns();
+        }
+        if write_headers {
+            self.write_headers();
+        }
+    }
+
+    /// Returns the columns of the CSV output for the current resources and processes, along
+    /// with the ones scheduled events will add. They stay the same for the whole output, a
+    /// column is empty while its resource or process doesn't exist.
+    fn output_columns(&self) -> Vec<String> {
+        let mut projected = self.clone();
+        for event in self.events.values() {
+            for modification in &event.modifications {
+                match modification {
+                    Modification::AddResource { name, resource } => projected.add_resource(name, *resource.clone()),
+                    Modification::AddProcess { name, process } => projected.add_process(name, *process.clone()),
+                    _ => {}
+                }
+            }
+        }
+        let mut columns = projected.headers();
+        // Columns of removed resources, which events could add back in the meantime
+        for header in self.headers() {
+            if !columns.contains(&header) {
+                columns.push(header);
+            }
+        }
+        columns
+    }
 
-        // Write the headers to the CSV file
+    /// Returns the columns for the current resources and processes.
+    fn headers(&self) -> Vec<String> {
         let mut headers = vec!["time".to_string()];
-        for resource_name in sim.resources.keys() {
+        for resource_name in self.resources.keys() {
             headers.push(resource_name.clone() + "_min");
             headers.push(resource_name.clone() + "_avg");
             headers.push(resource_name.clone() + "_max");
             headers.push(resource_name.clone());
         }
-        for process_name in sim.on_use_processes.keys() {
+        for process_name in self.on_use_processes.keys() {
             headers.push(process_name.clone());
         }
-        sim.csv_writer.write_record(&headers).unwrap();
+        for (process_name, _) in self.processes.iter().filter(|(_, process)| process.transport.is_some()) {
+            headers.push(process_name.clone() + "_in_transit");
+        }
+        for (process_name, _) in self.processes.iter().filter(|(_, process)| process.demand.is_some()) {
+            headers.push(process_name.clone() + "_backlog");
+            headers.push(process_name.clone() + "_fill_rate");
+            headers.push(process_name.clone() + "_lost");
+        }
+        for resource_name in self.ordered_resources() {
+            headers.push(resource_name + "_on_order");
+        }
+        if let Some(base_currency) = &self.base_currency {
+            headers.push(format!("value_{}", base_currency));
+        }
+        if self.has_valuation() {
+            headers.push("inventory_value".to_string());
+            headers.push("net_worth".to_string());
+        }
+        headers
+    }
 
-        sim
+    /// Writes the headers to the CSV file.
+    fn write_headers(&mut self) {
+        self.csv_writer.write_record(&self.columns).unwrap();
+    }
+
+    /// Adds a resource, or replaces the resource with the same name.
+    pub fn add_resource(&mut self, name: &str, resource: Resource) {
+        self.ledger_rates = None;
+        self.resources.insert(name.to_string(), resource);
+    }
+
+    /// Removes a resource. Processes using it can't run anymore and what they produce of it is lost.
+    pub fn remove_resource(&mut self, name: &str) -> Option<Resource> {
+        self.ledger_rates = None;
+        self.resources.shift_remove(name)
+    }
+
+    /// Adds a process, or replaces the process with the same name.
+    pub fn add_process(&mut self, name: &str, process: Process) {
+        if process.on_use > 0.0 {
+            self.on_use_processes.insert(name.to_string(), process);
+        } else {
+            self.processes.insert(name.to_string(), process);
+        }
+    }
+
+    /// Removes a process.
+    pub fn remove_process(&mut self, name: &str) -> Option<Process> {
+        self.processes.shift_remove(name).or_else(|| self.on_use_processes.shift_remove(name))
+    }
+
+    /// Returns a process to reconfigure it.
+    pub fn process_mut(&mut self, name: &str) -> Option<&mut Process> {
+        match self.processes.get_mut(name) {
+            Some(process) => Some(process),
+            None => self.on_use_processes.get_mut(name),
+        }
     }
 
     /// Sets the start time of the simulation.
     pub fn set_start_time(&mut self, time: DateTime<Utc>) {
         self.time = time;
+        self.start_time = time.timestamp() as u64;
         self.last_write_time = time.timestamp() as u64;
     }
 
     /// Runs the simulation for a given duration.
     pub fn run(&mut self, duration: u64) {
-        let start_time_nanoseconds = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_nanos();
-        for time_in_s in 0..duration {
-            self.simulate_tick();
-            // Adding one second to the time
-            self.time = self.time + chrono::Duration::seconds(1);
-            if time_in_s % self.write_every == 0 {
-                self.write_current_state_to_csv();
+        for _ in 0..duration {
+            self.step();
+        }
+    }
+
+    /// Runs the simulation for a given duration, calling `on_progress` after every output
+    /// record and once at the end, and stopping early once `cancel` is cancelled.
+    ///
+    /// A cancelled run stops between two seconds, so the output holds complete records up
+    /// to that time and the state can be checkpointed and resumed. Returns whether the whole
+    /// duration ran.
+    pub fn run_with(&mut self, duration: u64, cancel: &CancelToken, mut on_progress: impl FnMut(&Progress)) -> bool {
+        let start = self.time;
+        let progress = |sim: &Simulation| Progress {
+            simulated: (sim.time - start).num_seconds() as u64,
+            duration,
+            time: sim.time,
+        };
+        for _ in 0..duration {
+            if cancel.is_cancelled() {
+                on_progress(&progress(self));
+                return false;
             }
+            let written = self.last_write_time;
+            self.step();
+            if self.last_write_time != written {
+                on_progress(&progress(self));
+            }
+        }
+        on_progress(&progress(self));
+        true
+    }
+
+    /// Simulates one second and writes the state if a write interval starts with it.
+    /// Intervals count from the start time, so a run continues on the same records when resumed.
+    pub fn step(&mut self) {
+        let time_in_s = self.time.timestamp() as u64 - self.start_time;
+        self.notify(Observers::before_tick);
+        self.simulate_tick();
+        // Adding one second to the time
+        self.time += chrono::Duration::seconds(1);
+        if time_in_s.is_multiple_of(self.write_every) {
+            self.write_current_state_to_csv();
+        }
+        self.notify(Observers::after_tick);
+    }
+
+    /// Registers an observer called along the run. Forks and checkpoints don't keep it.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.add(observer);
+    }
+
+    /// Calls the observers with the simulation, taking them out meanwhile.
+    fn notify(&mut self, callback: impl FnOnce(&mut Observers, &Simulation)) {
+        if self.observers.is_empty() {
+            return;
         }
-        let end_time_nanoseconds = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_nanos();
-        println!("Simulation took {} seconds", (end_time_nanoseconds - start_time_nanoseconds) as f64 / 1_000_000_000.0);
+        let mut observers = std::mem::take(&mut self.observers);
+        callback(&mut observers, self);
+        self.observers = observers;
     }
 
     /// Simulates a single tick of the simulation.
     fn simulate_tick(&mut self) {
+        self.apply_due_events();
+        self.update_exchange_rates();
+        self.settle_instruments();
         self.decay_resources();
+        self.deliver_in_transit();
         self.deduct_on_use_processes();
         self.reset_amount_used_as_catalyst();
         self.run_processes();
@@ -74,7 +415,264 @@ impl Simulation {
         self.update_resource_min_max_avg();
     }
 
-    /// Updates the minimum, maximum, and average values for resources.
+    /// Applies the modifications of events whose time has come and drops them.
+    fn apply_due_events(&mut self) {
+        let now = self.time.timestamp() as u64;
+        let is_due = |event: &Event| match event.time {
+            EventTime::At(time) => time <= now,
+            EventTime::After(offset) => self.start_time + offset <= now,
+        };
+        if !self.events.values().any(is_due) {
+            return;
+        }
+        let due: Vec<String> = self.events.iter()
+            .filter(|(_, event)| is_due(event))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in due {
+            let event = self.events.shift_remove(&name).unwrap();
+            if let Err(e) = self.apply_modifications(&event.modifications) {
+                eprintln!("Event {} at {} failed: {}", name, self.time, e);
+            }
+        }
+    }
+
+    /// Moves exchange rates along their series and reprices the conversion processes.
+    fn update_exchange_rates(&mut self) {
+        if self.exchange_rates.is_empty() {
+            return;
+        }
+        let now = self.time.timestamp() as u64;
+        for exchange_rate in self.exchange_rates.values_mut() {
+            if exchange_rate.update(now) {
+                self.ledger_rates = None;
+            }
+        }
+        let rates: Vec<Option<f64>> = self.processes.values()
+            .filter(|process| process.convert)
+            .map(|process| {
+                let from = self.rate_of(process.input.keys().next()?)?;
+                let to = self.rate_of(process.output.keys().next()?)?;
+                Some(from / to)
+            })
+            .collect();
+        for (process, rate) in self.processes.values_mut().filter(|process| process.convert).zip(rates) {
+            let amount = process.input.values().next().copied().unwrap_or(0.0);
+            if let Some(converted) = process.output.values_mut().next() {
+                // Without a known rate nothing is produced, so the conversion is effectively stopped
+                *converted = amount * rate.unwrap_or(0.0);
+            }
+        }
+    }
+
+    /// Pays out loans once they start, then takes their payments and adds interest to savings every period.
+    ///
+    /// A payment the account can't cover is taken as far as possible, the rest stays on the loan.
+    /// Payments are skipped while the account doesn't exist.
+    fn settle_instruments(&mut self) {
+        if self.instruments.is_empty() {
+            return;
+        }
+        let now = self.time.timestamp() as u64;
+        for index in 0..self.instruments.len() {
+            let (name, instrument) = self.instruments.get_index(index).unwrap();
+            let opening = instrument.opened_at.is_none() && instrument.start.is_none_or(|start| now >= start);
+            let due = instrument.opened_at.is_some_and(|opened_at| now > opened_at)
+                && self.time_period_check(instrument.period, instrument.period_delta);
+            if !(opening || due) || !self.resources.contains_key(name) {
+                continue;
+            }
+            let name = name.clone();
+            let ledger_rates = self.ledger_rates();
+            let instrument = &self.instruments[index];
+            match instrument.kind {
+                InstrumentKind::Loan => {
+                    let account = instrument.account.clone().unwrap_or_default();
+                    if opening {
+                        let instrument = &mut self.instruments[index];
+                        instrument.opened_at = Some(now);
+                        instrument.amortize();
+                        let principal = instrument.principal;
+                        self.resources[&name].amount -= principal;
+                        if let Some(account) = self.resources.get_mut(&account) {
+                            account.add(principal, now);
+                        }
+                        if let Some(rate) = ledger_rates.get(&account) {
+                            self.ledger.record(&name, &account, 0.0, principal, *rate);
+                        }
+                        continue;
+                    }
+                    let owed = -self.resources[&name].amount;
+                    if owed <= 0.0 {
+                        continue;
+                    }
+                    let interest = owed * instrument.period_rate();
+                    // The last payment, and any after it while something is still owed, settle the loan
+                    let payment = if instrument.payments_left <= 1 { owed + interest } else { instrument.payment.min(owed + interest) };
+                    // Without the account nothing can be paid, so the loan is left as it is
+                    let Some(account_resource) = self.resources.get_mut(&account) else { continue };
+                    let payment = payment.min((account_resource.amount - account_resource.min).max(0.0));
+                    account_resource.remove(payment);
+                    if let Some(rate) = ledger_rates.get(&account) {
+                        self.ledger.record(&name, &account, payment, 0.0, *rate);
+                    }
+                    self.resources[&name].amount += payment - interest;
+                    let instrument = &mut self.instruments[index];
+                    instrument.payments_left = instrument.payments_left.saturating_sub(1);
+                }
+                InstrumentKind::Savings => {
+                    if opening {
+                        self.instruments[index].opened_at = Some(now);
+                        continue;
+                    }
+                    let interest = self.resources[&name].amount * instrument.period_rate();
+                    self.resources[&name].add(interest, now);
+                    if let Some(rate) = ledger_rates.get(&name) {
+                        self.ledger.record(&name, &name, 0.0, interest, *rate);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the value of one unit of a resource in the base currency, if it is a currency.
+    pub fn rate_of(&self, resource_name: &str) -> Option<f64> {
+        let unit = self.resources.get(resource_name)?.unit.to_lowercase();
+        if self.base_currency.as_deref() == Some(unit.as_str()) {
+            return Some(1.0);
+        }
+        self.exchange_rates.get(&unit).map(|exchange_rate| exchange_rate.rate)
+    }
+
+    /// Whether a resource holds money, i.e. its unit is a currency.
+    pub fn is_monetary(&self, resource_name: &str) -> bool {
+        self.rate_of(resource_name).is_some()
+            || self.resources.get(resource_name).is_some_and(|resource| units::lookup(&resource.unit).dimension.starts_with("currency:"))
+    }
+
+    /// Returns the monetary resources for the ledger with their rate into the base currency.
+    fn ledger_rates(&mut self) -> Arc<IndexMap<String, Option<f64>>> {
+        if !self.ledger.enabled {
+            // Shared rather than allocated for every use, as nothing is recorded
+            static NO_RATES: OnceLock<Arc<IndexMap<String, Option<f64>>>> = OnceLock::new();
+            return NO_RATES.get_or_init(Arc::default).clone();
+        }
+        if self.ledger_rates.is_none() {
+            let rates = self.resources.keys()
+                .filter(|name| self.is_monetary(name))
+                .map(|name| (name.clone(), self.base_currency.as_ref().and_then(|_| self.rate_of(name))))
+                .collect();
+            self.ledger_rates = Some(Arc::new(rates));
+        }
+        self.ledger_rates.clone().unwrap()
+    }
+
+    /// Returns the total value of all currency resources in the base currency.
+    pub fn consolidated_value(&self) -> f64 {
+        self.resources.iter()
+            .filter_map(|(name, resource)| Some(resource.amount * self.rate_of(name)?))
+            .sum()
+    }
+
+    /// Returns the value of one unit of a resource in the base currency.
+    ///
+    /// Resources with a `value` use it, currencies their exchange rate and anything else is worth nothing.
+    pub fn unit_value(&self, resource_name: &str) -> f64 {
+        let Some(resource) = self.resources.get(resource_name) else { return 0.0 };
+        match &resource.value {
+            Some(value) => value.eval(&|variable| self.value_variable(resource_name, variable)).unwrap_or_else(|e| {
+                eprintln!("Cannot value {}: {}", resource_name, e);
+                0.0
+            }),
+            None if self.is_monetary(resource_name) => self.rate_of(resource_name).unwrap_or(1.0),
+            None => 0.0,
+        }
+    }
+
+    /// Looks up a variable of a value expression.
+    fn value_variable(&self, resource_name: &str, variable: &str) -> Option<f64> {
+        match variable {
+            "amount" => Some(self.resources[resource_name].amount),
+            "days" => Some((self.time.timestamp() as u64).saturating_sub(self.start_time) as f64 / 86400.0),
+            _ => self.resources.get(variable).map(|resource| resource.amount).or_else(|| {
+                if self.base_currency.as_deref() == Some(variable) {
+                    Some(1.0)
+                } else {
+                    self.exchange_rates.get(variable).map(|exchange_rate| exchange_rate.rate)
+                }
+            }),
+        }
+    }
+
+    /// Returns the total value of a resource.
+    ///
+    /// Amounts which decay depreciate linearly over the life of the resource. Amounts on order are included.
+    pub fn resource_value(&self, resource_name: &str) -> f64 {
+        let Some(resource) = self.resources.get(resource_name) else { return 0.0 };
+        let mut amount = resource.amount;
+        if resource.life > 0 {
+            let now = self.time.timestamp() as u64;
+            for (decay_at, decay_amount) in resource.decay_at.iter().zip(&resource.decay_amount) {
+                let remaining = decay_at.saturating_sub(now).min(resource.life) as f64 / resource.life as f64;
+                amount -= decay_amount * (1.0 - remaining);
+            }
+        }
+        // What is on order has been paid for, so it counts at its full value
+        (amount + self.on_order(resource_name)) * self.unit_value(resource_name)
+    }
+
+    /// Counts the amounts on order per resource, keeping the resources counted before so nothing is allocated.
+    fn count_on_order(&mut self) {
+        for total in self.on_order_totals.values_mut() {
+            *total = 0.0;
+        }
+        for process in self.processes.values().filter(|process| !process.in_transit.is_empty()) {
+            let runs = process.runs_in_transit();
+            for (resource_name, amount) in &process.output {
+                match self.on_order_totals.get_mut(resource_name) {
+                    Some(total) => *total += amount * runs,
+                    None => {
+                        self.on_order_totals.insert(resource_name.clone(), amount * runs);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the amount of a resource which is on the way, produced by processes with a lead time.
+    pub fn on_order(&self, resource_name: &str) -> f64 {
+        self.processes.values()
+            .filter_map(|process| Some(process.output.get(resource_name)? * process.runs_in_transit()))
+            .fold(0.0, |total, amount| total + amount)
+    }
+
+    /// Returns the resources processes with a lead time produce, which get an on-order column.
+    fn ordered_resources(&self) -> Vec<String> {
+        self.resources.keys()
+            .filter(|name| self.processes.values().any(|process| process.lead_time > 0 && process.output.contains_key(*name)))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the total value of all resources which are not money.
+    pub fn inventory_value(&self) -> f64 {
+        self.resources.keys()
+            .filter(|name| !self.is_monetary(name))
+            .map(|name| self.resource_value(name))
+            .sum()
+    }
+
+    /// Returns the total value of all resources, money included.
+    pub fn net_worth(&self) -> f64 {
+        self.resources.keys().map(|name| self.resource_value(name)).sum()
+    }
+
+    /// Whether any resource has a value, which adds the valuation columns to the output.
+    pub fn has_valuation(&self) -> bool {
+        self.resources.values().any(|resource| resource.value.is_some())
+    }
+
+    /// Updates the minimum, maximum, and average values for resources along with the run statistics.
     fn update_resource_min_max_avg(&mut self) {
         for resource in self.resources.values_mut() {
             if resource.amount < resource.resource_min_for_writer {
@@ -84,31 +682,48 @@ impl Simulation {
                 resource.resource_max_for_writer = resource.amount;
             }
             resource.resource_avg_for_writer += resource.amount;
+            if resource.amount < resource.resource_min_for_run {
+                resource.resource_min_for_run = resource.amount;
+            }
+            if resource.amount <= resource.min {
+                resource.stockout_seconds += 1;
+            }
         }
     }
 
-    /// Deducts resources for on-use processes at the start.
+    /// Deducts resources for on-use processes at the start, noting which of them could be fed.
     fn deduct_on_use_processes(&mut self) {
-        for (_, process) in &mut self.on_use_processes {
-            let mut feasible = true;
+        let now = self.time.timestamp() as u64;
+        self.on_use_fed.clear();
+        for (process_name, process) in self.on_use_processes.iter_mut() {
+            let active = process.is_active(now);
+            let mut feasible = active;
+            let mut held_back_by = None;
             for (resource_name, amount) in &process.input {
                 if let Some(resource) = self.resources.get_mut(resource_name) {
-                    if resource.amount < *amount {
+                    if resource.amount - amount < resource.min {
                         feasible = false;
+                        held_back_by = Some(resource_name.as_str());
                         break;
                     }
                 }
             }
+            // What is drawn from it is added to the runs when the processes run
+            if active {
+                process.stats.record(0.0, process.on_use, held_back_by);
+                self.observers.on_process(self.time, process_name, process, if feasible { process.on_use } else { 0.0 });
+            }
             if feasible {
                 for (resource_name, amount) in &process.input {
                     if let Some(resource) = self.resources.get_mut(resource_name) {
                         resource.amount -= amount;
                     }
                 }
-                process.on_use_accumulate = -1.0 * process.on_use;
+                process.on_use_accumulate = -process.on_use;
             } else {
                 process.on_use_accumulate = 0.0;
             }
+            self.on_use_fed.push(feasible);
         }
     }
 
@@ -120,12 +735,21 @@ impl Simulation {
     }
 
     /// Adds back the remaining amount for on-use processes at the end.
+    /// The ledger gets what the processes which were fed actually used.
     fn add_back_on_use_processes(&mut self) {
-        for (_, process) in &mut self.on_use_processes {
+        let now = self.time.timestamp() as u64;
+        let ledger_rates = self.ledger_rates();
+        for ((process_name, process), fed) in self.on_use_processes.iter_mut().zip(&self.on_use_fed) {
+            if !process.is_active(now) {
+                continue;
+            }
             for (resource_name, amount) in &process.input {
                 if let Some(resource) = self.resources.get_mut(resource_name) {
-                    let addition = -1.0 * amount * process.on_use_accumulate / process.on_use;
+                    let addition = -amount * process.on_use_accumulate / process.on_use;
                     resource.amount += addition;
+                    if let (true, Some(rate)) = (fed, ledger_rates.get(resource_name)) {
+                        self.ledger.record(process_name, resource_name, amount - addition, 0.0, *rate);
+                    }
                 }
             }
             process.on_use_accumulate_for_writer += process.on_use_accumulate + process.on_use;
@@ -135,9 +759,10 @@ impl Simulation {
     /// Decays resources based on their decay schedule.
     fn decay_resources(&mut self) {
         let now = self.time.timestamp() as u64;
-        for (_, resource) in &mut self.resources {
+        for (resource_name, resource) in &mut self.resources {
             while !resource.decay_at.is_empty() && resource.decay_at[0] <= now {
                 resource.amount -= resource.decay_amount[0];
+                self.observers.on_decay(self.time, resource_name, resource.decay_amount[0]);
                 resource.decay_at.remove(0);
                 resource.decay_amount.remove(0);
             }
@@ -146,138 +771,234 @@ impl Simulation {
 
     /// Runs all processes in the simulation.
     fn run_processes(&mut self) {
-        for (_, process) in &self.processes {
-            let can_run = self.times_process_can_run(process);
-            if can_run > 0 {
-                for (resource_name, amount) in &process.input {
-                    if let Some(resource) = self.resources.get_mut(resource_name) {
-                        resource.amount -= amount * can_run as f64;
-                        // Deduct the decayed amount from the latest decay if exists
-                        if !resource.decay_at.is_empty() {
-                            let mut amount_to_deduct = amount * can_run as f64;
-                            for i in 0..resource.decay_at.len() {
-                                resource.decay_amount[i] -= amount_to_deduct;
-                                if resource.decay_amount[i] < 0.0 {
-                                    amount_to_deduct = -1.0 * resource.decay_amount[i];
-                                    resource.decay_amount[i] = 0.0;
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                    } else if let Some(resource) = self.on_use_processes.get_mut(resource_name) {
-                        resource.on_use_accumulate += amount * can_run as f64;
+        let ledger_rates = self.ledger_rates();
+        self.count_on_order();
+        for index in 0..self.processes.len() {
+            let process = &self.processes[index];
+            let due = self.is_due(process);
+            let times = if process.demand.is_some() {
+                self.serve_demand(index, due, &ledger_rates) as f64
+            } else if !due {
+                0.0
+            } else {
+                match &process.overdraft {
+                    Some(resource_name) => self.overdraft_penalty_times(resource_name),
+                    None => {
+                        // The stats are taken out while the process is checked, so what held it back needn't be copied
+                        let mut stats = std::mem::take(&mut self.processes[index].stats);
+                        let process = &self.processes[index];
+                        let (times, held_back_by) = self.times_process_can_run(process);
+                        stats.record(times as f64, process.max_catalyst as f64, held_back_by);
+                        self.processes[index].stats = stats;
+                        times as f64
                     }
                 }
-                for (resource_name, amount) in &process.catalyst {
-                    if let Some(resource) = self.resources.get_mut(resource_name) {
-                        resource.amount_used_as_catalyst += amount * can_run as f64;
-                    }
+            };
+            if due || times > 0.0 {
+                let (process_name, process) = self.processes.get_index(index).unwrap();
+                self.observers.on_process(self.time, process_name, process, times);
+            }
+            if times > 0.0 {
+                self.execute_process(index, times, &ledger_rates);
+            }
+        }
+    }
+
+    /// Takes the inputs of a process and adds its outputs for running it the given number of times.
+    ///
+    /// With a lead time the outputs are put in transit and only added once they arrive.
+    fn execute_process(&mut self, index: usize, times: f64, ledger_rates: &IndexMap<String, Option<f64>>) {
+        let (process_name, process) = self.processes.get_index_mut(index).unwrap();
+        for (resource_name, amount) in &process.input {
+            if let Some(resource) = self.resources.get_mut(resource_name) {
+                resource.remove(amount * times);
+                if let Some(rate) = ledger_rates.get(resource_name) {
+                    self.ledger.record(process_name, resource_name, amount * times, 0.0, *rate);
                 }
+            } else if let Some(on_use_process) = self.on_use_processes.get_mut(resource_name) {
+                on_use_process.on_use_accumulate += amount * times;
+                on_use_process.stats.runs += amount * times;
+            }
+        }
+        for (resource_name, amount) in &process.catalyst {
+            if let Some(resource) = self.resources.get_mut(resource_name) {
+                resource.amount_used_as_catalyst += amount * times;
+            }
+        }
 
-                // Add output resources
-                for (resource_name, amount) in &process.output {
-                    if let Some(resource) = self.resources.get_mut(resource_name) {
-                        resource.amount += amount * can_run as f64;
-                        if resource.life > 0 {
-                            resource.decay_at.push(self.time.timestamp() as u64 + resource.life);
-                            resource.decay_amount.push(*amount * can_run as f64);
-                        }
+        if process.lead_time > 0 {
+            process.in_transit.push((self.time.timestamp() as u64 + process.lead_time, times));
+            for (resource_name, amount) in &process.output {
+                match self.on_order_totals.get_mut(resource_name) {
+                    Some(total) => *total += amount * times,
+                    None => {
+                        self.on_order_totals.insert(resource_name.clone(), amount * times);
                     }
                 }
             }
+            return;
         }
+        self.add_outputs(index, times, ledger_rates);
     }
 
-    /// Determines how many times a process can run based on available resources and constraints.
-    fn times_process_can_run(&self, process: &Process) -> u64 {
-        // Check if the time is right for the process along with the constraints
-        if !self.time_period_check(process.period, process.period_delta) {
-            return 0;
+    /// Adds the outputs of a process for the given number of runs.
+    fn add_outputs(&mut self, index: usize, times: f64, ledger_rates: &IndexMap<String, Option<f64>>) {
+        let (process_name, process) = self.processes.get_index(index).unwrap();
+        for (resource_name, amount) in &process.output {
+            if let Some(resource) = self.resources.get_mut(resource_name) {
+                resource.add(amount * times, self.time.timestamp() as u64);
+                if let Some(rate) = ledger_rates.get(resource_name) {
+                    self.ledger.record(process_name, resource_name, 0.0, amount * times, *rate);
+                }
+            }
         }
-        if !self.constraint_check(&process.constraint, &process.constraint_modulo) {
+    }
+
+    /// Adds the outputs which arrive now.
+    fn deliver_in_transit(&mut self) {
+        let now = self.time.timestamp() as u64;
+        if !self.processes.values().any(|process| process.in_transit.first().is_some_and(|(arrival, _)| *arrival <= now)) {
+            return;
+        }
+        let ledger_rates = self.ledger_rates();
+        for index in 0..self.processes.len() {
+            let process = &mut self.processes[index];
+            let arrived = process.in_transit.partition_point(|(arrival, _)| *arrival <= now);
+            let runs: f64 = process.in_transit.drain(..arrived).map(|(_, runs)| runs).sum();
+            if runs > 0.0 {
+                self.add_outputs(index, runs, &ledger_rates);
+            }
+        }
+    }
+
+    /// Places the orders of a demand process if it is due and returns how many orders can be served now.
+    ///
+    /// Waiting orders are served as soon as there is enough stock, whether the process is due or not,
+    /// at most `max_catalyst` of them at once.
+    /// Orders which expire are lost and their penalty is charged, even below the `min` of a resource.
+    fn serve_demand(&mut self, index: usize, due: bool, ledger_rates: &IndexMap<String, Option<f64>>) -> u64 {
+        let now = self.time.timestamp() as u64;
+        if !self.processes[index].is_active(now) {
             return 0;
         }
+        let demand = self.processes[index].demand.as_mut().unwrap();
+        if due {
+            demand.backlog.push((now, demand.orders));
+            demand.ordered_for_writer += demand.orders;
+        }
+        let process = &self.processes[index];
+        let limit = process.demand.as_ref().unwrap().waiting().min(process.max_catalyst);
+        let served = if limit > 0 { self.times_resources_allow(process, limit).0 } else { 0 };
+
+        let (process_name, process) = self.processes.get_index_mut(index).unwrap();
+        let demand = process.demand.as_mut().unwrap();
+        demand.serve(served, now);
+        let lost = demand.expire(now);
+        if lost > 0 {
+            for (resource_name, amount) in &demand.penalty {
+                if let Some(resource) = self.resources.get_mut(resource_name) {
+                    resource.remove(amount * lost as f64);
+                    if let Some(rate) = ledger_rates.get(resource_name) {
+                        self.ledger.record(process_name, resource_name, amount * lost as f64, 0.0, *rate);
+                    }
+                }
+            }
+        }
+        served
+    }
 
+    /// Returns the overdrawn amount of a resource an overdraft penalty process is charged for.
+    ///
+    /// Penalties are always charged in full, even if that takes a resource below its `min`.
+    fn overdraft_penalty_times(&self, resource_name: &str) -> f64 {
+        self.resources.get(resource_name).map_or(0.0, |resource| (-resource.amount).max(0.0))
+    }
+
+    /// Determines how many times a due process can run, and what held it back from `max_catalyst` runs:
+    /// a resource, or `capacity` if too many runs are on their way.
+    fn times_process_can_run<'a>(&'a self, process: &Process) -> (u64, Option<&'a str>) {
+        // Runs in transit take up the capacity until they arrive
+        let limit = match process.capacity {
+            Some(capacity) => process.max_catalyst.min(capacity.saturating_sub(process.runs_in_transit() as u64)),
+            None => process.max_catalyst,
+        };
+        if limit == 0 {
+            return (0, Some("capacity"));
+        }
+        let (can_run, held_back_by) = self.times_resources_allow(process, limit);
+        if can_run == limit && limit < process.max_catalyst {
+            return (can_run, Some("capacity"));
+        }
+        (can_run, held_back_by)
+    }
+
+    /// Determines how many times, up to a limit, the resources allow a process to run, and which
+    /// resource held it below the limit.
+    fn times_resources_allow<'a>(&'a self, process: &Process, limit: u64) -> (u64, Option<&'a str>) {
         // Check if the process has enough catalyst resources
-        let mut can_run = process.max_catalyst;
+        let mut can_run = limit;
+        let mut held_back_by = None;
         for (resource_name, amount) in &process.catalyst {
-            if let Some(resource) = self.resources.get(resource_name) {
+            if let Some((resource_name, resource)) = self.resources.get_key_value(resource_name) {
                 let amount_can_use = ((resource.amount - resource.amount_used_as_catalyst) / *amount) as u64;
                 if amount_can_use < can_run {
                     can_run = amount_can_use;
+                    held_back_by = Some(resource_name.as_str());
                 }
                 if can_run == 0 {
-                    return 0;
+                    return (0, held_back_by);
                 }
             } else {
-                return 0;
+                return (0, None);
             }
         }
 
         // Check if the process has enough input resources
         for (resource_name, amount) in &process.input {
-            if let Some(resource) = self.resources.get(resource_name) {
-                let amount_can_use = (resource.amount - resource.amount_used_as_catalyst) / *amount;
-                if amount_can_use < can_run as f64 {
-                    can_run = amount_can_use as u64;
-                }
-                if can_run == 0 {
-                    return 0;
-                }
-            } else if let Some(on_use_process) = self.on_use_processes.get(resource_name) {
-                let amount_can_use = -1.0 * on_use_process.on_use_accumulate / *amount;
-                if amount_can_use < can_run as f64 {
-                    can_run = amount_can_use as u64;
-                }
-                if can_run == 0 {
-                    return 0;
-                }
+            let (resource_name, amount_can_use) = if let Some((name, resource)) = self.resources.get_key_value(resource_name) {
+                (name, (resource.amount - resource.amount_used_as_catalyst - resource.min) / *amount)
+            } else if let Some((name, on_use_process)) = self.on_use_processes.get_key_value(resource_name) {
+                (name, -on_use_process.on_use_accumulate / *amount)
             } else {
-                return 0;
+                return (0, None);
+            };
+            if amount_can_use < can_run as f64 {
+                can_run = amount_can_use as u64;
+                held_back_by = Some(resource_name.as_str());
+            }
+            if can_run == 0 {
+                return (0, held_back_by);
             }
         }
 
-        // Check if output resources are not exceeding their maximum
+        // Check if output resources are not exceeding their maximum, counting what any process has on order
         for (resource_name, amount) in &process.output {
-            if let Some(resource) = self.resources.get(resource_name) {
-                let amount_can_use = (resource.max - resource.amount) / *amount;
+            if let Some((resource_name, resource)) = self.resources.get_key_value(resource_name) {
+                let on_order = if resource.max < f64::MAX { self.on_order_totals.get(resource_name).copied().unwrap_or(0.0) } else { 0.0 };
+                let amount_can_use = (resource.max - resource.amount - on_order) / *amount;
                 if amount_can_use < can_run as f64 {
                     can_run = amount_can_use as u64;
+                    held_back_by = Some(resource_name.as_str());
                 }
                 if can_run == 0 {
-                    return 0;
+                    return (0, held_back_by);
                 }
             }
         }
-        can_run
+        (can_run, held_back_by)
+    }
+
+    /// Checks if the process is active and the time is right for it along with the constraints.
+    fn is_due(&self, process: &Process) -> bool {
+        process.is_active(self.time.timestamp() as u64)
+            && self.time_period_check(process.period, process.period_delta)
+            && process.constraints_allow(self.time.timestamp() as u64)
     }
 
     /// Checks if the current time is within the process's period constraints.
     fn time_period_check(&self, period: u64, period_delta: u64) -> bool {
         let now = self.time.timestamp() as u64;
-        (now - period_delta) % period == 0
-    }
-
-    /// Checks if the current time satisfies the process's constraints.
-    fn constraint_check(&self, constraint: &Vec<Vec<[u64; 2]>>, constraint_modulo: &Vec<u64>) -> bool {
-        let now = self.time.timestamp() as u64;
-        for (i, constraint_list) in constraint.iter().enumerate() {
-            let modulo = constraint_modulo[i];
-            let mut feasible_for_this_modulo = false;
-            let time_to_check = now % modulo;
-            for [start, end] in constraint_list {
-                if (*start <= *end && time_to_check >= *start && time_to_check <= *end) ||
-                   (*start > *end && (time_to_check >= *start || time_to_check <= *end)) {
-                    feasible_for_this_modulo = true;
-                    break;
-                }
-            }
-            if !feasible_for_this_modulo {
-                return false;
-            }
-        }
-        true
+        (now - period_delta).is_multiple_of(period)
     }
 
     /// Displays the current state of resources.
@@ -292,10 +1013,18 @@ impl Simulation {
                 resource.max,
             );
         }
+        if let Some(base_currency) = &self.base_currency {
+            println!("Consolidated value: {} {}", self.consolidated_value(), base_currency);
+        }
+        if self.has_valuation() {
+            println!("Inventory value: {}, net worth: {}", self.inventory_value(), self.net_worth());
+        }
     }
 
     /// Writes the current state of the simulation to a CSV file.
     fn write_current_state_to_csv(&mut self) {
+        let headers = self.headers();
+
         // timestamp, resource_0_amount, resource_1_amount, ...
         let mut record = vec![self.time.to_string()];
         for resource in self.resources.values_mut() {
@@ -304,14 +1033,274 @@ impl Simulation {
             record.push(resource.resource_max_for_writer.to_string());
             record.push(resource.amount.to_string());
             resource.resource_min_for_writer = f64::MAX;
-            resource.resource_max_for_writer = 0.0;
+            resource.resource_max_for_writer = f64::MIN;
             resource.resource_avg_for_writer = 0.0;
         }
         for process in self.on_use_processes.values_mut() {
             record.push(process.on_use_accumulate_for_writer.to_string());
             process.on_use_accumulate_for_writer = 0.0;
         }
+        for process in self.processes.values().filter(|process| process.transport.is_some()) {
+            record.push(process.runs_in_transit().to_string());
+        }
+        for demand in self.processes.values_mut().filter_map(|process| process.demand.as_mut()) {
+            record.push(demand.waiting().to_string());
+            // Share of the orders of the interval served right away, empty if nothing was ordered
+            if demand.ordered_for_writer > 0 {
+                record.push((demand.filled_for_writer as f64 / demand.ordered_for_writer as f64).to_string());
+            } else {
+                record.push(String::new());
+            }
+            record.push(demand.lost_for_writer.to_string());
+            demand.ordered_for_writer = 0;
+            demand.filled_for_writer = 0;
+            demand.lost_for_writer = 0;
+        }
+        for resource_name in self.ordered_resources() {
+            record.push(self.on_order(&resource_name).to_string());
+        }
+        if self.base_currency.is_some() {
+            record.push(self.consolidated_value().to_string());
+        }
+        if self.has_valuation() {
+            record.push(self.inventory_value().to_string());
+            record.push(self.net_worth().to_string());
+        }
+        // Resources or processes were added or removed, so put the values in the columns of the output
+        if headers != self.columns {
+            let mut values: IndexMap<String, String> = headers.into_iter().zip(record).collect();
+            record = self.columns.iter().map(|column| values.swap_remove(column).unwrap_or_default()).collect();
+        }
         self.csv_writer.write_record(&record).unwrap();
+        self.notify(|observers, sim| observers.on_write(sim, &record));
+        self.ledger.close_period(self.last_write_time, self.time.timestamp() as u64);
         self.last_write_time = self.time.timestamp() as u64;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_model, parse_simulation_file};
+
+    fn simulation(reson_file: &str) -> Simulation {
+        start(parse_simulation_file(reson_file).unwrap())
+    }
+
+    /// Simulation of a model written out in the test.
+    fn simulation_of(contents: &str) -> Simulation {
+        start(parse_model(contents).unwrap())
+    }
+
+    fn start(model: Model) -> Simulation {
+        let mut sim = Simulation::new(model, Box::new(io::sink()));
+        sim.set_start_time(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        sim.write_every = 3600;
+        sim
+    }
+
+    /// Output shared with the test, so it can be read once the simulation has written it.
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn columns_stay_fixed_when_resources_change() {
+        let mut sim = simulation("example/simple_pencil.reson");
+        let output = SharedOutput::default();
+        sim.events.insert("add_box".to_string(), Event {
+            time: EventTime::After(7200),
+            modifications: vec![Modification::AddResource { name: "box".to_string(), resource: Box::new(Resource { amount: 5.0, ..Resource::default() }) }],
+        });
+        sim.events.insert("remove_wood".to_string(), Event {
+            time: EventTime::After(14400),
+            modifications: vec![Modification::RemoveResource { name: "wood".to_string() }],
+        });
+        sim.set_writer(Box::new(output.clone()), true);
+        sim.run(6 * 3600);
+        sim.csv_writer.flush().unwrap();
+
+        let data = output.0.borrow().clone();
+        let mut reader = csv::Reader::from_reader(data.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|header| header == name).unwrap();
+        let records: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 6);
+        // Empty until the resource is added, and after it is removed
+        assert_eq!(&records[0][column("box")], "");
+        assert_eq!(&records[3][column("box")], "5");
+        assert_ne!(&records[3][column("wood")], "");
+        assert_eq!(&records[5][column("wood")], "");
+    }
+
+    #[test]
+    fn demand_serves_at_most_max_catalyst_orders_at_once() {
+        let mut sim = simulation_of("pencil\n    resource\n    amount 10\n\nsell_pencil\n    demand\n    orders 5\n    use\n        pencil 1\n    catalyze 2\n    period 1 h\n");
+
+        sim.run(1);
+        assert_eq!(sim.resources["pencil"].amount, 8.0);
+        sim.run(10);
+        assert_eq!(sim.resources["pencil"].amount, 5.0);
+    }
+
+    #[test]
+    fn orders_of_every_process_count_towards_the_max() {
+        let supplier = |name: &str| format!("{}\n    process\n    produce\n        stock 6\n    period 1 h\n    lead_time 2 h\n\n", name);
+        let model = format!("stock\n    resource\n    max 10\n\n{}{}", supplier("supplier_a"), supplier("supplier_b"));
+        let mut sim = simulation_of(&model);
+
+        sim.run(1);
+        assert_eq!(sim.on_order("stock"), 6.0);
+        sim.run(2 * 3600);
+        assert_eq!(sim.resources["stock"].amount, 6.0);
+    }
+
+    #[test]
+    fn transports_deliver_after_their_lead_time_within_their_capacity() {
+        let mut sim = simulation_of("pencil\n    resource\n    locations factory store\n\npencil@factory\n    resource\n    amount 100\n\ntruck\n    transport\n    from factory\n    to store\n    move\n        pencil 10\n    lead_time 3 h\n    capacity 2\n    period 1 h\n");
+
+        sim.run(1);
+        assert_eq!(sim.resources["pencil@factory"].amount, 90.0);
+        assert_eq!(sim.on_order("pencil@store"), 10.0);
+        // With two loads on the road the third waits until the first arrives
+        sim.run(2 * 3600);
+        assert_eq!(sim.resources["pencil@factory"].amount, 80.0);
+        assert_eq!(sim.resources["pencil@store"].amount, 0.0);
+        assert_eq!(sim.processes["truck"].stats.held_back_by["capacity"], 1);
+        sim.run(3600);
+        assert_eq!(sim.resources["pencil@store"].amount, 10.0);
+        assert_eq!(sim.resources["pencil@factory"].amount, 70.0);
+    }
+
+    #[test]
+    fn run_with_reports_progress_and_stops_once_cancelled() {
+        let mut sim = simulation("example/simple_pencil.reson");
+        let cancel = CancelToken::default();
+        let mut reported = Vec::new();
+        // Cancelled from the progress callback once the second record is written
+        let completed = sim.run_with(10 * 3600, &cancel, |progress| {
+            reported.push(progress.simulated);
+            if progress.simulated > 3600 {
+                cancel.cancel();
+            }
+        });
+        assert!(!completed);
+        assert_eq!(reported, [1, 3601, 3601]);
+        assert_eq!(sim.time.timestamp() as u64 - sim.start_time, 3601);
+
+        let mut fractions = Vec::new();
+        assert!(sim.run_with(2 * 3600, &CancelToken::default(), |progress| fractions.push(progress.fraction())));
+        assert_eq!(fractions.last(), Some(&1.0));
+        assert_eq!(sim.time.timestamp() as u64 - sim.start_time, 3 * 3600 + 1);
+        // Nothing runs with a token cancelled before the start
+        assert!(!sim.run_with(3600, &cancel, |_| {}));
+        assert_eq!(sim.time.timestamp() as u64 - sim.start_time, 3 * 3600 + 1);
+    }
+
+    /// Records the processes reported to observers, shared with the test.
+    #[derive(Clone, Default)]
+    struct ProcessLog(std::rc::Rc<std::cell::RefCell<Vec<(String, f64)>>>);
+
+    impl Observer for ProcessLog {
+        fn on_process(&mut self, _time: DateTime<Utc>, name: &str, _process: &Process, can_run: f64) {
+            self.0.borrow_mut().push((name.to_string(), can_run));
+        }
+    }
+
+    #[test]
+    fn every_process_execution_is_reported() {
+        let mut sim = simulation("example/pencil_demand.reson");
+        let log = ProcessLog::default();
+        sim.add_observer(Box::new(log.clone()));
+        sim.run(1);
+        assert_eq!(*log.0.borrow(), [("make_pencil".to_string(), 1.0), ("sell_pencil".to_string(), 1.0)]);
+        // The second order waits and is served although the demand isn't due
+        sim.run(1);
+        assert_eq!(log.0.borrow()[2..], [("sell_pencil".to_string(), 1.0)]);
+
+        let mut sim = simulation("example/simple_pencil.reson");
+        let log = ProcessLog::default();
+        sim.add_observer(Box::new(log.clone()));
+        sim.run(1);
+        assert!(log.0.borrow().contains(&("electirc_intake".to_string(), 3.0)), "{:?}", log.0.borrow());
+    }
+
+    #[test]
+    fn loans_are_paid_back_over_their_term() {
+        let mut sim = simulation_of("cad\n    resource\n    unit cad\n    amount 5000\n\nmachine_loan\n    loan\n    account cad\n    principal 12000\n    interest 12 %\n    term 12 d\n    period 1 d\n");
+        sim.run(1);
+        assert_eq!(sim.resources["cad"].amount, 17000.0);
+        assert_eq!(sim.resources["machine_loan"].amount, -12000.0);
+        assert_eq!(sim.resources["machine_loan"].unit, "cad");
+
+        let payment = sim.instruments["machine_loan"].payment;
+        sim.run(14 * 86400);
+        assert!(sim.resources["machine_loan"].amount.abs() < 1e-6, "{}", sim.resources["machine_loan"].amount);
+        assert_eq!(sim.instruments["machine_loan"].payments_left, 0);
+        assert!((sim.resources["cad"].amount - (17000.0 - 12.0 * payment)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn loan_payments_are_skipped_without_the_account() {
+        let mut sim = simulation_of("cad\n    resource\n    unit cad\n    amount 5000\n\nmachine_loan\n    loan\n    account cad\n    principal 12000\n    interest 12 %\n    term 12 d\n    period 1 d\n");
+        sim.run(1);
+        sim.apply_modifications(&[Modification::RemoveResource { name: "cad".to_string() }]).unwrap();
+        sim.run(3 * 86400);
+        assert_eq!(sim.resources["machine_loan"].amount, -12000.0);
+        assert_eq!(sim.instruments["machine_loan"].payments_left, 12);
+    }
+
+    #[test]
+    fn ledger_records_money_moved_by_every_process() {
+        let mut sim = simulation_of("cad\n    resource\n    unit cad\n    amount 1000\n\nwood\n    resource\n\nbuy_wood\n    process\n    use\n        cad 10\n    produce\n        wood 1\n    period 1 h\n\nsell_wood\n    process\n    use\n        wood 1\n    produce\n        cad 15\n    period 1 h\n");
+        sim.ledger.enabled = true;
+        sim.run(2 * 3600);
+
+        let start = sim.start_time;
+        let entries: Vec<(u64, u64, &str, &str, f64, f64)> = sim.ledger.entries.iter()
+            .map(|entry| (entry.period_start - start, entry.period_end - start, entry.process.as_str(), entry.resource.as_str(), entry.debit, entry.credit))
+            .collect();
+        assert_eq!(entries, vec![
+            (0, 1, "buy_wood", "cad", 10.0, 0.0),
+            (0, 1, "sell_wood", "cad", 0.0, 15.0),
+            (1, 3601, "buy_wood", "cad", 10.0, 0.0),
+            (1, 3601, "sell_wood", "cad", 0.0, 15.0),
+        ]);
+        // Without a base currency nothing is converted
+        assert!(sim.ledger.entries.iter().all(|entry| entry.net_base.is_none()));
+
+        let by_process: Vec<(String, f64, f64)> = sim.ledger.by_process().into_iter()
+            .map(|entry| (entry.process, entry.debit, entry.credit))
+            .collect();
+        assert_eq!(by_process, vec![("buy_wood".to_string(), 20.0, 0.0), ("sell_wood".to_string(), 0.0, 30.0)]);
+        let by_period: Vec<(u64, u64, f64, f64)> = sim.ledger.by_period().into_iter()
+            .map(|entry| (entry.period_start - start, entry.period_end - start, entry.debit, entry.credit))
+            .collect();
+        assert_eq!(by_period, vec![(0, 1, 10.0, 15.0), (1, 3601, 10.0, 15.0)]);
+        assert_eq!(sim.resources["cad"].amount, 1010.0);
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut sim = simulation("example/food_factory.reson");
+        sim.run(86400 + 1234);
+        let path = std::env::temp_dir().join(format!("resim_checkpoint_{}.json", std::process::id()));
+        sim.save_checkpoint(&path).unwrap();
+        let mut resumed = Simulation::load_checkpoint(&path).unwrap();
+        assert_eq!(serde_json::to_value(&resumed).unwrap(), serde_json::to_value(&sim).unwrap());
+
+        // The resumed run carries on exactly like the original one
+        sim.run(86400);
+        resumed.run(86400);
+        assert_eq!(serde_json::to_value(&resumed).unwrap(), serde_json::to_value(&sim).unwrap());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::parameter::set_parameter;
+use crate::resource::Model;
+use crate::simulation::Simulation;
+use chrono::prelude::*;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// A model parameter together with the values it takes in the sweep.
+#[derive(Debug, Clone)]
+pub struct SweepParameter {
+    pub path: String,
+    pub values: Vec<f64>,
+}
+
+impl SweepParameter {
+    /// Parses `path=value_1,value_2,...` or `path=start:end:step`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (path, values) = spec.split_once('=')
+            .ok_or_else(|| format!("Invalid sweep parameter {}, expected path=values", spec))?;
+        let parse_value = |value: &str| value.trim().parse::<f64>().map_err(|_| format!("Invalid value {} in {}", value, spec));
+
+        let values = if values.contains(':') {
+            let range: Vec<f64> = values.split(':').map(parse_value).collect::<Result<_, _>>()?;
+            if range.len() != 3 || range[2] <= 0.0 {
+                return Err(format!("Invalid range in {}, expected start:end:step", spec));
+            }
+            range_values(range[0], range[1], range[2])
+        } else {
+            values.split(',').map(parse_value).collect::<Result<_, _>>()?
+        };
+        if values.is_empty() {
+            return Err(format!("No values in sweep parameter {}", spec));
+        }
+        Ok(SweepParameter { path: path.trim().to_string(), values })
+    }
+}
+
+/// Returns the values from `start` to `end` (inclusive, allowing for rounding) in steps of `step`.
+pub fn range_values(start: f64, end: f64, step: f64) -> Vec<f64> {
+    let mut values = Vec::new();
+    let mut value = start;
+    while value <= end + step * 1e-9 {
+        values.push(value);
+        value += step;
+    }
+    values
+}
+
+/// Returns every combination of the parameter values, the last parameter varying fastest.
+pub fn scenarios(parameters: &[SweepParameter]) -> Vec<Vec<f64>> {
+    let mut scenarios = vec![Vec::new()];
+    for parameter in parameters {
+        scenarios = scenarios.into_iter()
+            .flat_map(|scenario| parameter.values.iter().map(move |value| {
+                let mut scenario = scenario.clone();
+                scenario.push(*value);
+                scenario
+            }))
+            .collect();
+    }
+    scenarios
+}
+
+/// Runs a simulation for every combination of the parameter values.
+///
+/// The time series of each scenario is written to `scenario_<n>.csv` in `out_dir` and
+/// `summary.csv` gets one row per scenario with the final amount, minimum amount and
+/// stockout time (seconds spent at or below its `min`) of every resource, plus the final
+/// net worth if any resource has a value. Unless `quiet`, every scenario is announced as it starts.
+#[allow(clippy::too_many_arguments)]
+pub fn run_sweep(
+    model: &Model,
+    parameters: &[SweepParameter],
+    start_time: DateTime<Utc>,
+    write_every: u64,
+    run_for: u64,
+    out_dir: &Path,
+    quiet: bool,
+) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    let mut summary = csv::Writer::from_path(out_dir.join("summary.csv"))?;
+
+    // Resources added or removed during a run don't shift the columns
+    let resource_names: Vec<String> = model.resources.keys().cloned().collect();
+    let mut headers = vec!["scenario".to_string()];
+    headers.extend(parameters.iter().map(|parameter| parameter.path.clone()));
+    for resource_name in &resource_names {
+        headers.push(resource_name.clone() + "_final");
+        headers.push(resource_name.clone() + "_min");
+        headers.push(resource_name.clone() + "_stockout_s");
+    }
+    let valued = model.resources.values().any(|resource| resource.value.is_some());
+    if valued {
+        headers.push("net_worth_final".to_string());
+    }
+    summary.write_record(&headers)?;
+
+    for (index, values) in scenarios(parameters).iter().enumerate() {
+        let mut model = model.clone();
+        for (parameter, value) in parameters.iter().zip(values) {
+            set_parameter(&mut model.resources, &mut model.processes, &mut model.on_use_processes, &parameter.path, *value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let name = format!("scenario_{}", index);
+        if !quiet {
+            println!("Running {}: {}", name, parameters.iter().zip(values)
+                .map(|(parameter, value)| format!("{}={}", parameter.path, value))
+                .collect::<Vec<_>>()
+                .join(" "));
+        }
+        let file = File::create(out_dir.join(format!("{}.csv", name)))?;
+        let mut sim = Simulation::new(model, Box::new(file));
+        sim.set_start_time(start_time);
+        sim.write_every = write_every;
+        sim.run(run_for);
+        sim.csv_writer.flush()?;
+
+        let mut record = vec![name];
+        record.extend(values.iter().map(f64::to_string));
+        // Resources removed during the run are left empty
+        for resource_name in &resource_names {
+            match sim.resources.get(resource_name) {
+                Some(resource) => {
+                    record.push(resource.amount.to_string());
+                    record.push(resource.resource_min_for_run.to_string());
+                    record.push(resource.stockout_seconds.to_string());
+                }
+                None => record.extend([String::new(), String::new(), String::new()]),
+            }
+        }
+        if valued {
+            record.push(sim.net_worth().to_string());
+        }
+        summary.write_record(&record)?;
+    }
+    summary.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_simulation_file;
+    use crate::resource::{Event, EventTime, Modification};
+
+    #[test]
+    fn parameters_and_ranges() {
+        let parameter = SweepParameter::parse("pencil_machine.amount = 1, 2,4").unwrap();
+        assert_eq!(parameter.path, "pencil_machine.amount");
+        assert_eq!(parameter.values, [1.0, 2.0, 4.0]);
+        assert_eq!(SweepParameter::parse("wood.max=0.1:0.3:0.1").unwrap().values.len(), 3);
+        assert!(SweepParameter::parse("wood.max").is_err());
+        assert!(SweepParameter::parse("wood.max=1:2").is_err());
+        assert!(SweepParameter::parse("wood.max=1:2:0").is_err());
+        assert!(SweepParameter::parse("wood.max=a,2").is_err());
+    }
+
+    #[test]
+    fn scenarios_combine_every_value() {
+        let parameters = [
+            SweepParameter { path: "a".to_string(), values: vec![1.0, 2.0] },
+            SweepParameter { path: "b".to_string(), values: vec![10.0, 20.0, 30.0] },
+        ];
+        let scenarios = scenarios(&parameters);
+        assert_eq!(scenarios.len(), 6);
+        assert_eq!(scenarios[0], [1.0, 10.0]);
+        assert_eq!(scenarios[1], [1.0, 20.0]);
+        assert_eq!(scenarios[5], [2.0, 30.0]);
+        assert_eq!(super::scenarios(&[]), [Vec::<f64>::new()]);
+    }
+
+    #[test]
+    fn summary_columns_stay_with_their_resources() {
+        let mut model = parse_simulation_file("example/simple_pencil.reson").unwrap();
+        model.events.insert("remove_wood".to_string(), Event {
+            time: EventTime::After(3600),
+            modifications: vec![Modification::RemoveResource { name: "wood".to_string() }],
+        });
+        let out_dir = std::env::temp_dir().join(format!("resim_sweep_{}", std::process::id()));
+        let parameters = [SweepParameter::parse("pencil.max=10,20").unwrap()];
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        run_sweep(&model, &parameters, start, 3600, 7200, &out_dir, true).unwrap();
+
+        let mut reader = csv::Reader::from_path(out_dir.join("summary.csv")).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let records: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>().unwrap();
+        fs::remove_dir_all(&out_dir).unwrap();
+        assert_eq!(records.len(), 2);
+        let column = |name: &str| headers.iter().position(|header| header == name).unwrap();
+        for (record, max) in records.iter().zip(["10", "20"]) {
+            assert_eq!(record.len(), headers.len());
+            assert_eq!(&record[column("pencil.max")], max);
+            assert_eq!(&record[column("wood_final")], "");
+            assert_ne!(&record[column("pencil_final")], "");
+        }
+    }
+}
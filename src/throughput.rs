@@ -0,0 +1,296 @@
+use crate::inspect::format_duration;
+use crate::resource::{Model, Process};
+use indexmap::IndexMap;
+use std::fmt::Write;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Most due times sampled to find the share a process's constraints let through.
+const MAX_SAMPLES: u64 = 1_000_000;
+
+/// The highest rate a process can run at and what holds it there.
+#[derive(Debug, Clone)]
+pub struct ProcessRate {
+    pub name: String,
+    /// Runs per day, or for on-use processes the amount drawn from them per day.
+    /// `None` if the rate depends on the state of the run, like for overdraft penalties.
+    pub per_day: Option<f64>,
+    pub limited_by: String,
+}
+
+/// How much of a resource all processes produce and use per day at their highest rates.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceFlow {
+    pub unit: String,
+    pub amount: f64,
+    pub min: f64,
+    pub produced_per_day: f64,
+    pub used_per_day: f64,
+}
+
+impl ResourceFlow {
+    pub fn net_per_day(&self) -> f64 {
+        self.produced_per_day - self.used_per_day
+    }
+
+    /// Days until the resource reaches its minimum if it keeps being used faster than it is produced.
+    pub fn days_until_empty(&self) -> Option<f64> {
+        let net = self.net_per_day();
+        (net < 0.0 && self.min > f64::MIN).then(|| ((self.amount - self.min) / -net).max(0.0))
+    }
+}
+
+/// Steady-state rates of a model, computed from the process definitions without simulating.
+///
+/// Every process is assumed to run as often as its period, constraints and `max_catalyst` allow,
+/// so the rates are upper bounds: inputs, catalysts and output maxima can only hold it back further.
+pub struct Throughput {
+    pub processes: Vec<ProcessRate>,
+    pub resources: IndexMap<String, ResourceFlow>,
+}
+
+impl Throughput {
+    pub fn analyze(model: &Model) -> Self {
+        let mut resources: IndexMap<String, ResourceFlow> = model.resources.iter()
+            .map(|(name, resource)| (name.clone(), ResourceFlow {
+                unit: resource.unit.clone(),
+                amount: resource.amount,
+                min: resource.min,
+                ..Default::default()
+            }))
+            .collect();
+        let mut processes = Vec::new();
+        let mut drawn_from_on_use: IndexMap<&str, f64> = IndexMap::new();
+
+        for (name, process) in &model.processes {
+            let (per_day, limited_by) = process_rate(model, process);
+            if let Some(runs) = per_day {
+                for (resource_name, quantity) in &process.input {
+                    if model.on_use_processes.contains_key(resource_name) {
+                        *drawn_from_on_use.entry(resource_name).or_insert(0.0) += quantity * runs;
+                    } else if let Some(flow) = resources.get_mut(resource_name) {
+                        flow.used_per_day += quantity * runs;
+                    }
+                }
+                for (resource_name, quantity) in &process.output {
+                    if let Some(flow) = resources.get_mut(resource_name) {
+                        flow.produced_per_day += quantity * runs;
+                    }
+                }
+            }
+            processes.push(ProcessRate { name: name.clone(), per_day, limited_by });
+        }
+
+        // On-use processes take their inputs in proportion to how much of them is drawn
+        for (name, process) in &model.on_use_processes {
+            let drawn = drawn_from_on_use.get(name.as_str()).copied().unwrap_or(0.0);
+            for (resource_name, quantity) in &process.input {
+                if let Some(flow) = resources.get_mut(resource_name) {
+                    flow.used_per_day += quantity * drawn / process.on_use;
+                }
+            }
+            let capacity = process.on_use * SECONDS_PER_DAY;
+            processes.push(ProcessRate {
+                name: name.clone(),
+                per_day: Some(drawn),
+                limited_by: format!("on use, {:.0} % of {} per day", 100.0 * drawn / capacity, capacity),
+            });
+        }
+
+        Throughput { processes, resources }
+    }
+
+    /// Returns the resources used faster than they are produced, which run out eventually.
+    pub fn negative_balances(&self) -> Vec<(&String, &ResourceFlow)> {
+        self.resources.iter().filter(|(_, flow)| flow.net_per_day() < -1e-9).collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "Processes at their highest rates:").unwrap();
+        writeln!(text, "  {:<32}{:>14}  limited by", "process", "runs/day").unwrap();
+        for rate in &self.processes {
+            let per_day = rate.per_day.map_or("-".to_string(), format_rate);
+            writeln!(text, "  {:<32}{:>14}  {}", rate.name, per_day, rate.limited_by).unwrap();
+        }
+
+        writeln!(text, "Resource flows per day:").unwrap();
+        writeln!(text, "  {:<32}{:>14}{:>14}{:>14}  unit", "resource", "produced", "used", "net").unwrap();
+        for (name, flow) in &self.resources {
+            writeln!(text, "  {:<32}{:>14.2}{:>14.2}{:>14.2}  {}", name, flow.produced_per_day, flow.used_per_day, flow.net_per_day(), flow.unit).unwrap();
+        }
+
+        let negative = self.negative_balances();
+        if !negative.is_empty() {
+            writeln!(text, "Used faster than produced:").unwrap();
+            for (name, flow) in negative {
+                let until_empty = flow.days_until_empty()
+                    .map_or("no minimum".to_string(), |days| format!("at its minimum after {:.1} days", days));
+                writeln!(text, "  {}: {:.2} {} per day, {}", name, flow.net_per_day(), flow.unit, until_empty).unwrap();
+            }
+        }
+        text
+    }
+}
+
+/// Formats a rate with enough decimals for processes running less than once a day.
+fn format_rate(per_day: f64) -> String {
+    if per_day != 0.0 && per_day.abs() < 1.0 {
+        format!("{:.4}", per_day)
+    } else {
+        format!("{:.2}", per_day)
+    }
+}
+
+/// Returns the highest runs per day of a periodic process and what limits it.
+fn process_rate(model: &Model, process: &Process) -> (Option<f64>, String) {
+    if !process.enabled {
+        return (Some(0.0), "disabled".to_string());
+    }
+    if let Some(resource_name) = &process.overdraft {
+        return (None, format!("overdraft of {}", resource_name));
+    }
+    if process.period == 0 {
+        return (Some(0.0), "no period".to_string());
+    }
+    let due_per_day = due_share(process) * SECONDS_PER_DAY / process.period as f64;
+    let mut limited_by = format!("period {}", format_duration(process.period));
+    if !process.constraint.is_empty() {
+        limited_by.push_str(" and constraints");
+    }
+
+    // Demands are served whenever stock allows, so they run as often as orders come in
+    if let Some(demand) = &process.demand {
+        return (Some(due_per_day * demand.orders as f64), format!("{}, {} orders", limited_by, demand.orders));
+    }
+
+    let mut runs_per_due = process.max_catalyst as f64;
+    let mut limit = format!("{}, max_catalyst {}", limited_by, process.max_catalyst);
+    // Every run draws from an on-use process within the same second
+    for (resource_name, quantity) in &process.input {
+        if let Some(on_use_process) = model.on_use_processes.get(resource_name) {
+            let runs = (on_use_process.on_use / quantity).floor();
+            if runs < runs_per_due {
+                runs_per_due = runs;
+                limit = format!("{}, on use of {}", limited_by, resource_name);
+            }
+        }
+    }
+    let mut per_day = due_per_day * runs_per_due;
+    if let (Some(capacity), true) = (process.capacity, process.lead_time > 0) {
+        let capacity_per_day = capacity as f64 * SECONDS_PER_DAY / process.lead_time as f64;
+        if capacity_per_day < per_day {
+            per_day = capacity_per_day;
+            limit = format!("capacity {} per lead time", capacity);
+        }
+    }
+    (Some(per_day), limit)
+}
+
+/// Returns the share of a process's due times its constraints let through, sampled over
+/// the time it takes the period and the constraint cycles to line up again.
+fn due_share(process: &Process) -> f64 {
+    if process.constraint.is_empty() {
+        return 1.0;
+    }
+    let mut cycle = process.period;
+    for modulo in &process.constraint_modulo {
+        cycle = lcm(cycle, *modulo).min(process.period.saturating_mul(MAX_SAMPLES));
+    }
+    let samples = (cycle / process.period).clamp(1, MAX_SAMPLES);
+    let allowed = (0..samples)
+        .filter(|k| process.constraints_allow(process.period_delta + k * process.period))
+        .count();
+    allowed as f64 / samples as f64
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    (a / x).saturating_mul(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+
+    #[test]
+    fn rates_and_flows() {
+        let model = parse_model("\
+wood
+    resource
+    amount 192
+
+power
+    resource
+    amount 100
+
+pencil
+    resource
+
+electric
+    process
+    on_use 5
+    use
+        power 1
+
+make_pencil
+    process
+    use
+        wood 2
+        electric 2
+    produce
+        pencil 1
+    catalyze 4
+    period 1 h
+
+ship_pencil
+    process
+    use
+        pencil 1
+    lead_time 2 h
+    capacity 1
+    period 1 h
+
+idle
+    process
+    produce
+        pencil 1
+    period 1 h
+    enabled false
+").unwrap();
+        let throughput = Throughput::analyze(&model);
+        let rates: Vec<(&str, Option<f64>, &str)> = throughput.processes.iter()
+            .map(|rate| (rate.name.as_str(), rate.per_day, rate.limited_by.as_str()))
+            .collect();
+        assert_eq!(rates, [
+            // Two runs an hour draw all 5 of the on-use process, below the max_catalyst of 4
+            ("make_pencil", Some(48.0), "period 1h, on use of electric"),
+            ("ship_pencil", Some(12.0), "capacity 1 per lead time"),
+            ("idle", Some(0.0), "disabled"),
+            ("electric", Some(96.0), "on use, 0 % of 432000 per day"),
+        ]);
+
+        let wood = &throughput.resources["wood"];
+        assert_eq!((wood.produced_per_day, wood.used_per_day), (0.0, 96.0));
+        assert_eq!(wood.days_until_empty(), Some(2.0));
+        assert_eq!(throughput.resources["power"].used_per_day, 96.0 / 5.0);
+        let pencil = &throughput.resources["pencil"];
+        assert_eq!(pencil.net_per_day(), 36.0);
+        assert_eq!(pencil.days_until_empty(), None);
+        let negative: Vec<&String> = throughput.negative_balances().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(negative, ["wood", "power"]);
+        assert!(throughput.describe().contains("  wood: -96.00  per day, at its minimum after 2.0 days\n"), "{}", throughput.describe());
+    }
+    #[test]
+    fn constraints_reduce_the_due_share() {
+        let model = parse_model("make\n    process\n    period 1 h\n    constraint\n        h 10-16\n").unwrap();
+        assert_eq!(due_share(&model.processes["make"]), 7.0 / 24.0);
+        let (per_day, limited_by) = process_rate(&model, &model.processes["make"]);
+        assert_eq!(per_day, Some(7.0));
+        assert_eq!(limited_by, "period 1h and constraints, max_catalyst 1");
+    }
+}
@@ -0,0 +1,326 @@
+use crate::inspect::format_duration;
+use crate::observer::Observer;
+use crate::simulation::Simulation;
+use chrono::DateTime;
+use indexmap::IndexMap;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Samples kept per resource for its sparkline.
+const HISTORY: usize = 200;
+
+/// Time spent simulating between two redraws.
+const FRAME: Duration = Duration::from_millis(50);
+
+/// Width of the resource names and amounts left of the sparklines.
+const LABEL_WIDTH: u16 = 44;
+
+/// Amounts of every resource, sampled once per write interval.
+#[derive(Default)]
+struct History(IndexMap<String, VecDeque<f64>>);
+
+impl History {
+    fn sample(&mut self, sim: &Simulation) {
+        // Resources added or removed by events appear or disappear with them
+        self.0.retain(|name, _| sim.resources.contains_key(name));
+        for (name, resource) in &sim.resources {
+            let history = self.0.entry(name.clone()).or_default();
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(resource.amount);
+        }
+    }
+}
+
+/// Samples the resources whenever the simulation writes a record, shared with the dashboard.
+struct Sampler(Rc<RefCell<History>>);
+
+impl Observer for Sampler {
+    fn on_write(&mut self, sim: &Simulation, _record: &[String]) {
+        self.0.borrow_mut().sample(sim);
+    }
+}
+
+/// The state of the dashboard besides the simulation itself.
+struct Dashboard {
+    duration: u64,
+    simulated: u64,
+    paused: bool,
+    /// Wall time spent simulating, without the time paused.
+    running_time: Duration,
+    /// Seconds simulated within `running_time`, single steps aren't counted.
+    running_simulated: u64,
+    selected: usize,
+    show_batches: bool,
+    history: Rc<RefCell<History>>,
+}
+
+impl Dashboard {
+    /// Creates the dashboard, which samples the resources from now on.
+    fn new(sim: &mut Simulation, duration: u64) -> Self {
+        let history = Rc::new(RefCell::new(History::default()));
+        history.borrow_mut().sample(sim);
+        sim.add_observer(Box::new(Sampler(history.clone())));
+        Dashboard {
+            duration,
+            simulated: 0,
+            paused: false,
+            running_time: Duration::ZERO,
+            running_simulated: 0,
+            selected: 0,
+            show_batches: false,
+            history,
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.simulated >= self.duration
+    }
+
+    /// Simulated seconds per wall second while running.
+    fn speed(&self) -> f64 {
+        let seconds = self.running_time.as_secs_f64();
+        if seconds > 0.0 { self.running_simulated as f64 / seconds } else { 0.0 }
+    }
+
+    fn step(&mut self, sim: &mut Simulation) {
+        sim.step();
+        self.simulated += 1;
+    }
+
+    /// Simulates until the frame time is up or the run is over.
+    fn run_frame(&mut self, sim: &mut Simulation) {
+        let started = Instant::now();
+        let simulated = self.simulated;
+        while !self.finished() && started.elapsed() < FRAME {
+            // Checking the clock is slow compared to a tick
+            for _ in 0..64 {
+                if self.finished() {
+                    break;
+                }
+                self.step(sim);
+            }
+        }
+        self.running_time += started.elapsed();
+        self.running_simulated += self.simulated - simulated;
+    }
+
+    /// Handles a key press, returns false if the dashboard should close.
+    fn handle_key(&mut self, key: KeyCode, sim: &mut Simulation) -> bool {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') | KeyCode::Char('p') => self.paused = !self.paused,
+            KeyCode::Char('s') | KeyCode::Right => {
+                self.paused = true;
+                if !self.finished() {
+                    self.step(sim);
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.selected += 1,
+            KeyCode::Char('d') | KeyCode::Enter => self.show_batches = !self.show_batches,
+            _ => {}
+        }
+        self.selected = self.selected.min(sim.resources.len().saturating_sub(1));
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame, sim: &Simulation) {
+        let [status, progress, body, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+
+        let state = if self.finished() {
+            "finished"
+        } else if self.paused {
+            "paused"
+        } else {
+            "running"
+        };
+        frame.render_widget(Paragraph::new(format!(
+            "{}  {}  {:.0} simulated s/s  {}",
+            sim.time,
+            state,
+            self.speed(),
+            if sim.has_valuation() { format!("net worth {:.2}", sim.net_worth()) } else { String::new() }
+        )), status);
+
+        let ratio = if self.duration > 0 { self.simulated as f64 / self.duration as f64 } else { 1.0 };
+        frame.render_widget(Gauge::default()
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio.min(1.0))
+            .label(format!("{} of {}", format_duration(self.simulated), format_duration(self.duration))), progress);
+
+        if self.show_batches {
+            let [resources, batches] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+            self.draw_resources(frame, sim, resources);
+            self.draw_batches(frame, sim, batches);
+        } else {
+            self.draw_resources(frame, sim, body);
+        }
+
+        let keys = if self.finished() {
+            "↑↓ select  d decay batches  q quit"
+        } else {
+            "space pause  s step a second  ↑↓ select  d decay batches  q stop and quit"
+        };
+        frame.render_widget(Paragraph::new(keys).style(Style::default().fg(Color::DarkGray)), help);
+    }
+
+    /// Draws a row per resource with its amount and a sparkline of its recent amounts.
+    fn draw_resources(&self, frame: &mut Frame, sim: &Simulation, area: Rect) {
+        let block = Block::bordered().title("Resources");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let rows = inner.height as usize;
+        // Scroll so the selected resource stays visible
+        let offset = (self.selected + 1).saturating_sub(rows);
+        let history = self.history.borrow();
+        for (row, (name, resource)) in sim.resources.iter().enumerate().skip(offset).take(rows) {
+            let area = Rect { y: inner.y + (row - offset) as u16, height: 1, ..inner };
+            let [label, sparkline] = Layout::horizontal([Constraint::Length(LABEL_WIDTH), Constraint::Min(0)]).areas(area);
+            let style = if row == self.selected { Style::default().add_modifier(Modifier::REVERSED) } else { Style::default() };
+            let text = format!("{:<24.24}{:>12.2} {}", name, resource.amount, resource.unit);
+            frame.render_widget(Paragraph::new(text).style(style), label);
+
+            if let Some(history) = history.0.get(name) {
+                frame.render_widget(Sparkline::default()
+                    .data(scale(history, sparkline.width as usize))
+                    .max(8)
+                    .style(Style::default().fg(Color::Green)), sparkline);
+            }
+        }
+    }
+
+    /// Draws the pending decays of the selected resource, soonest first.
+    fn draw_batches(&self, frame: &mut Frame, sim: &Simulation, area: Rect) {
+        let Some((name, resource)) = sim.resources.get_index(self.selected) else { return };
+        let now = sim.time.timestamp() as u64;
+        let mut lines = Vec::new();
+        if resource.life == 0 {
+            lines.push(Line::from(format!("{} doesn't decay", name)));
+        } else {
+            lines.push(Line::from(format!("Life {}, {} batches", format_duration(resource.life), resource.decay_at.len())));
+            for (decay_at, amount) in resource.decay_at.iter().zip(&resource.decay_amount) {
+                let time = DateTime::from_timestamp(*decay_at as i64, 0).map_or(decay_at.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+                lines.push(Line::from(format!("{}  in {:<8} {:>12.2}", time, format_duration(decay_at.saturating_sub(now)), amount)));
+            }
+        }
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(format!("Decay batches of {}", name))), area);
+    }
+}
+
+/// Scales the latest samples that fit the width to the eight levels of a sparkline row.
+fn scale(history: &VecDeque<f64>, width: usize) -> Vec<u64> {
+    let samples: Vec<f64> = history.iter().skip(history.len().saturating_sub(width)).copied().collect();
+    let min = samples.iter().copied().fold(f64::MAX, f64::min);
+    let max = samples.iter().copied().fold(f64::MIN, f64::max);
+    samples.iter().map(|amount| {
+        if max > min {
+            (1.0 + 7.0 * (amount - min) / (max - min)).round() as u64
+        } else {
+            // A flat line sits in the middle
+            4
+        }
+    }).collect()
+}
+
+/// Runs the simulation for a given duration with a dashboard in the terminal.
+///
+/// The run can be paused, stepped a second at a time and stopped early, in which case the
+/// simulation is left at the time it was stopped at. Returns whether the whole duration ran.
+pub fn run(sim: &mut Simulation, duration: u64) -> io::Result<bool> {
+    let mut terminal = ratatui::try_init()?;
+    let result = run_dashboard(&mut terminal, sim, duration);
+    ratatui::try_restore()?;
+    result
+}
+
+fn run_dashboard(terminal: &mut DefaultTerminal, sim: &mut Simulation, duration: u64) -> io::Result<bool> {
+    let mut dashboard = Dashboard::new(sim, duration);
+    loop {
+        if !dashboard.paused {
+            dashboard.run_frame(sim);
+        }
+        terminal.draw(|frame| dashboard.draw(frame, sim))?;
+
+        // Wait for keys only when there's nothing to simulate
+        let timeout = if dashboard.paused || dashboard.finished() { Duration::from_millis(200) } else { Duration::ZERO };
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                // The terminal is in raw mode, so Ctrl-C arrives as a key rather than a signal
+                let interrupted = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (interrupted || !dashboard.handle_key(key.code, sim)) {
+                    return Ok(dashboard.finished());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+
+    #[test]
+    fn scale_spreads_the_latest_samples_over_eight_levels() {
+        let history: VecDeque<f64> = [100.0, 0.0, 5.0, 10.0].into_iter().collect();
+        assert_eq!(scale(&history, 3), [1, 5, 8]);
+        assert_eq!(scale(&history, 10), [8, 1, 1, 2]);
+        assert_eq!(scale(&[3.0, 3.0].into_iter().collect(), 10), [4, 4]);
+        assert!(scale(&VecDeque::new(), 10).is_empty());
+    }
+
+    #[test]
+    fn history_samples_every_write_and_keys_step_the_run() {
+        let model = parse_model("wood\n    resource\n    amount 10\n\nmake_pencil\n    process\n    use\n        wood 1\n    period 1 s\n").unwrap();
+        let mut sim = Simulation::new(model, Box::new(io::sink()));
+        sim.write_every = 2;
+        let mut dashboard = Dashboard::new(&mut sim, 3);
+
+        // Stepping pauses the run and stops at its end
+        for _ in 0..4 {
+            assert!(dashboard.handle_key(KeyCode::Char('s'), &mut sim));
+        }
+        assert!(dashboard.paused);
+        assert!(dashboard.finished());
+        assert_eq!(sim.resources["wood"].amount, 7.0);
+        // Sampled when the dashboard opens and after the first and third second
+        assert_eq!(dashboard.history.borrow().0["wood"], [10.0, 9.0, 7.0]);
+
+        assert!(dashboard.handle_key(KeyCode::Down, &mut sim));
+        assert_eq!(dashboard.selected, 0);
+        assert!(!dashboard.handle_key(KeyCode::Char('q'), &mut sim));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_samples_of_existing_resources() {
+        let model = parse_model("wood\n    resource\n    amount 1\n").unwrap();
+        let mut sim = Simulation::new(model, Box::new(io::sink()));
+        let mut history = History::default();
+        history.0.insert("removed".to_string(), VecDeque::from([1.0]));
+        for _ in 0..HISTORY + 1 {
+            history.sample(&sim);
+        }
+        assert_eq!(history.0.keys().collect::<Vec<_>>(), ["wood"]);
+        assert_eq!(history.0["wood"].len(), HISTORY);
+        sim.resources["wood"].amount = 2.0;
+        history.sample(&sim);
+        assert_eq!(history.0["wood"].back(), Some(&2.0));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::resource::{Process, Resource};
+use indexmap::IndexMap;
+
+/// A unit of measurement: its dimension and the factor converting it into the base unit of the dimension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    pub dimension: String,
+    pub factor: f64,
+}
+
+/// Known units as (names, dimension, factor to the base unit of the dimension).
+const UNITS: &[(&[&str], &str, f64)] = &[
+    // Mass, base gram
+    (&["mg", "milligram"], "mass", 0.001),
+    (&["g", "gram"], "mass", 1.0),
+    (&["kg", "kilogram"], "mass", 1000.0),
+    (&["t", "tonne", "ton"], "mass", 1_000_000.0),
+    // Volume, base liter
+    (&["ml", "milliliter", "millilitre"], "volume", 0.001),
+    (&["l", "liter", "litre"], "volume", 1.0),
+    (&["m3", "cubic_meter"], "volume", 1000.0),
+    // Energy, base watt hour
+    (&["j", "joule"], "energy", 1.0 / 3600.0),
+    (&["wh", "watt_hour"], "energy", 1.0),
+    (&["kwh", "kilowatt_hour"], "energy", 1000.0),
+    (&["mwh", "megawatt_hour"], "energy", 1_000_000.0),
+    // Time, base second
+    (&["second", "work_second"], "time", 1.0),
+    (&["minute", "min"], "time", 60.0),
+    (&["hour", "work_hour"], "time", 3600.0),
+    (&["day"], "time", 86400.0),
+];
+
+/// Currency codes, each is a dimension of its own until exchange rates are known.
+const CURRENCIES: &[&str] = &["cad", "usd", "eur", "gbp", "jpy", "chf", "aud", "cny", "inr"];
+
+/// Looks up a unit. Unknown units like `count` or `loaf` are a dimension of their own.
+pub fn lookup(unit: &str) -> Unit {
+    let name = unit.to_lowercase();
+    for (names, dimension, factor) in UNITS {
+        if names.contains(&name.as_str()) {
+            return Unit { dimension: dimension.to_string(), factor: *factor };
+        }
+    }
+    if CURRENCIES.contains(&name.as_str()) {
+        return Unit { dimension: format!("currency:{}", name), factor: 1.0 };
+    }
+    Unit { dimension: format!("unit:{}", name), factor: 1.0 }
+}
+
+/// Returns the factor converting an amount in unit `from` into unit `to`,
+/// or `None` if their dimensions are incompatible.
+pub fn conversion_factor(from: &str, to: &str) -> Option<f64> {
+    let from = lookup(from);
+    let to = lookup(to);
+    if from.dimension == to.dimension {
+        Some(from.factor / to.factor)
+    } else {
+        None
+    }
+}
+
+/// Converts the quantities of processes written with a unit (`wood 10 kg`) into the
+/// unit of the resource, rejecting units of an incompatible dimension.
+pub fn convert_process_units(resources: &IndexMap<String, Resource>, processes: &mut IndexMap<String, Process>) -> Result<(), String> {
+    for (process_name, process) in processes {
+        for (key, unit) in &process.units {
+            let (list, resource_name) = key.split_once('.').unwrap();
+            let resource = resources.get(resource_name)
+                .ok_or_else(|| format!("{} gives {} in {}, but {} is not a resource with a unit", process_name, resource_name, unit, resource_name))?;
+            let factor = conversion_factor(unit, &resource.unit).ok_or_else(|| format!(
+                "{} {} {} in {}, which is incompatible with the unit {} of {}",
+                process_name, list, resource_name, unit, resource.unit, resource_name,
+            ))?;
+            let quantities = match list {
+                "use" => &mut process.input,
+                "produce" => &mut process.output,
+                _ => &mut process.catalyst,
+            };
+            if let Some(quantity) = quantities.get_mut(resource_name) {
+                *quantity *= factor;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion_factors() {
+        assert_eq!(conversion_factor("kg", "gram"), Some(1000.0));
+        assert_eq!(conversion_factor("g", "tonne"), Some(0.000001));
+        assert_eq!(conversion_factor("ml", "L"), Some(0.001));
+        assert_eq!(conversion_factor("kWh", "wh"), Some(1000.0));
+        assert_eq!(conversion_factor("hour", "minute"), Some(60.0));
+        assert_eq!(conversion_factor("count", "count"), Some(1.0));
+        assert_eq!(conversion_factor("kg", "liter"), None);
+        assert_eq!(conversion_factor("count", "loaf"), None);
+        assert_eq!(conversion_factor("cad", "usd"), None);
+    }
+
+    #[test]
+    fn process_quantities_are_converted_into_the_resource_unit() {
+        let resources: IndexMap<String, Resource> = [
+            ("flour".to_string(), Resource { unit: "gram".to_string(), ..Resource::default() }),
+            ("water".to_string(), Resource { unit: "liter".to_string(), ..Resource::default() }),
+        ].into_iter().collect();
+        let mut process = Process::default();
+        process.input.insert("flour".to_string(), 2.0);
+        process.output.insert("water".to_string(), 500.0);
+        process.units.insert("use.flour".to_string(), "kg".to_string());
+        process.units.insert("produce.water".to_string(), "ml".to_string());
+        let mut processes: IndexMap<String, Process> = [("bake".to_string(), process)].into_iter().collect();
+
+        convert_process_units(&resources, &mut processes).unwrap();
+        assert_eq!(processes["bake"].input["flour"], 2000.0);
+        assert_eq!(processes["bake"].output["water"], 0.5);
+
+        // Mass can't be given in liters
+        processes["bake"].units.insert("use.flour".to_string(), "l".to_string());
+        let error = convert_process_units(&resources, &mut processes).unwrap_err();
+        assert!(error.contains("incompatible"), "{}", error);
+    }
+}
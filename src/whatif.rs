@@ -0,0 +1,142 @@
+use crate::parser::parse_simulation_file;
+use crate::resource::Modification;
+use crate::simulation::Simulation;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// A named what-if branch and the modifications applied when it is forked.
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub name: String,
+    pub modifications: Vec<Modification>,
+}
+
+impl Branch {
+    /// Parses `name=modification,modification,...` where a modification is one of
+    /// `add:resource:amount`, `set:path:value`, `remove:process` or `add_process:file.reson`.
+    ///
+    /// The name becomes the file name of the branch output, so it is limited to letters,
+    /// digits, `_` and `-`, and can't be `baseline`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (name, modifications) = spec.split_once('=').unwrap_or((spec, ""));
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("Invalid branch name {}, use letters, digits, _ and -", name));
+        }
+        if name == "baseline" {
+            return Err("The branch name baseline is taken by the baseline run".to_string());
+        }
+        let mut branch = Branch { name: name.to_string(), modifications: Vec::new() };
+        for modification in modifications.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let tokens: Vec<&str> = modification.split(':').collect();
+            let parse_value = |value: &str| value.parse::<f64>().map_err(|_| format!("Invalid value {} in {}", value, modification));
+            match tokens.as_slice() {
+                ["add", resource, amount] => branch.modifications.push(Modification::AddAmount {
+                    resource: resource.to_string(),
+                    amount: parse_value(amount)?,
+                }),
+                ["set", path, value] => branch.modifications.push(Modification::SetParameter {
+                    path: path.to_string(),
+                    value: parse_value(value)?,
+                }),
+                ["remove", name] => branch.modifications.push(Modification::RemoveProcess { name: name.to_string() }),
+                ["add_process", file] => {
+                    // Only the processes of the file are added, its resources must already exist
+                    let model = parse_simulation_file(file).map_err(|e| e.to_string())?;
+                    for (name, process) in model.processes.into_iter().chain(model.on_use_processes) {
+                        branch.modifications.push(Modification::AddProcess { name, process: Box::new(process) });
+                    }
+                }
+                _ => return Err(format!("Invalid modification {} in branch {}", modification, branch.name)),
+            }
+        }
+        Ok(branch)
+    }
+}
+
+/// Runs the baseline for `fork_after` seconds, then forks it into the branches and runs
+/// each of them for another `run_for` seconds.
+///
+/// The baseline is written to `baseline.csv` and every branch to `<branch>.csv` in `out_dir`.
+/// Unless `quiet`, every branch is announced as it starts.
+pub fn run_whatif(mut baseline: Simulation, branches: &[Branch], fork_after: u64, run_for: u64, out_dir: &Path, quiet: bool) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    baseline.set_writer(Box::new(File::create(out_dir.join("baseline.csv"))?), true);
+    baseline.run(fork_after);
+    baseline.csv_writer.flush()?;
+
+    let mut results = Vec::new();
+    for branch in branches {
+        let mut sim = baseline.fork();
+        sim.apply_modifications(&branch.modifications)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Branch {}: {}", branch.name, e)))?;
+        sim.set_writer(Box::new(File::create(out_dir.join(format!("{}.csv", branch.name)))?), true);
+        if !quiet {
+            println!("Running branch {}", branch.name);
+        }
+        sim.run(run_for);
+        sim.csv_writer.flush()?;
+        results.push((branch.name.clone(), sim));
+    }
+
+    // Compare the final state of the branches side by side
+    println!("Final amounts at {}:", results.first().map_or(baseline.time, |(_, sim)| sim.time));
+    println!("{:<24}{}", "resource", results.iter().map(|(name, _)| format!("{:>20}", name)).collect::<String>());
+    for resource_name in baseline.resources.keys() {
+        let amounts: String = results.iter()
+            .map(|(_, sim)| sim.resources.get(resource_name).map_or("-".to_string(), |r| r.amount.to_string()))
+            .map(|amount| format!("{:>20}", amount))
+            .collect();
+        println!("{:<24}{}", resource_name, amounts);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_model;
+    use chrono::prelude::*;
+
+    #[test]
+    fn branches() {
+        let branch = Branch::parse("rush = add:cad:5000, set:make.max_catalyst:2,remove:sell").unwrap();
+        assert_eq!(branch.name, "rush");
+        assert_eq!(branch.modifications.len(), 3);
+        assert!(matches!(&branch.modifications[0], Modification::AddAmount { resource, amount } if resource == "cad" && *amount == 5000.0));
+        assert!(matches!(&branch.modifications[2], Modification::RemoveProcess { name } if name == "sell"));
+        assert!(Branch::parse("same").unwrap().modifications.is_empty());
+
+        assert!(Branch::parse("rush=add:cad").is_err());
+        assert!(Branch::parse("rush=add:cad:lots").is_err());
+        assert!(Branch::parse("baseline=add:cad:1").is_err());
+        assert!(Branch::parse("../x=add:cad:1").is_err());
+        assert!(Branch::parse("a/b").is_err());
+        assert!(Branch::parse("=add:cad:1").is_err());
+    }
+
+    #[test]
+    fn branches_fork_from_the_baseline() {
+        let model = parse_model("cad\n    resource\n    amount 10\n\nstock\n    resource\n\nmake\n    process\n    produce\n        stock 1\n    period 1 h\n").unwrap();
+        let mut baseline = Simulation::new(model, Box::new(io::sink()));
+        baseline.set_start_time(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        baseline.write_every = 3600;
+        let branches = [Branch::parse("same").unwrap(), Branch::parse("rush=add:cad:5,set:make.max_catalyst:2").unwrap()];
+        let out_dir = std::env::temp_dir().join(format!("resim_whatif_{}", std::process::id()));
+        run_whatif(baseline, &branches, 7200, 7200, &out_dir, true).unwrap();
+
+        let last_row = |name: &str| {
+            let mut reader = csv::Reader::from_path(out_dir.join(format!("{}.csv", name))).unwrap();
+            let headers = reader.headers().unwrap().clone();
+            let record = reader.records().last().unwrap().unwrap();
+            let value = |column: &str| record[headers.iter().position(|header| header == column).unwrap()].parse::<f64>().unwrap();
+            (value("cad"), value("stock"))
+        };
+        let results = (last_row("baseline"), last_row("same"), last_row("rush"));
+        fs::remove_dir_all(&out_dir).unwrap();
+        assert_eq!(results.0, (10.0, 2.0));
+        assert_eq!(results.1, (10.0, 4.0));
+        assert_eq!(results.2, (15.0, 6.0));
+    }
+}